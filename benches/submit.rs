@@ -0,0 +1,32 @@
+// Submit cost with and without `Rring::register_ring_fd`. Each iteration
+// submits a single nop and reaps its completion, so the difference is the fd
+// lookup the registered path skips on every `io_uring_enter`. That lookup is
+// only expensive once the fd table is shared, so a thread is kept parked for
+// the duration, as in any multi-threaded application.
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rring::{Rring, SetupFlag};
+
+fn submit_nop(ring: &Rring) {
+    ring.get_sqe().unwrap().nop();
+    ring.submit().unwrap();
+    let cqe = ring.wait().unwrap();
+    ring.seen(cqe);
+}
+
+fn submit(c: &mut Criterion) {
+    let parked = thread::spawn(thread::park);
+    let mut group = c.benchmark_group("submit_nop");
+    let ring = Rring::new(64, SetupFlag::empty()).unwrap();
+    group.bench_function("plain_fd", |b| b.iter(|| submit_nop(&ring)));
+    let mut ring = Rring::new(64, SetupFlag::empty()).unwrap();
+    ring.register_ring_fd().unwrap();
+    group.bench_function("registered_fd", |b| b.iter(|| submit_nop(&ring)));
+    group.finish();
+    parked.thread().unpark();
+    parked.join().unwrap();
+}
+
+criterion_group!(benches, submit);
+criterion_main!(benches);
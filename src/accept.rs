@@ -0,0 +1,76 @@
+use std::{
+    mem::size_of,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
+
+use libc::{sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t};
+
+// Room for the peer address of an accept. It travels as the data of the
+// operation's `UserData` (see `Sqe::accept_slot`), so it stays put until the
+// kernel has written to it and comes back with the completion.
+pub struct AcceptSlot {
+    pub(crate) storage: sockaddr_storage,
+    pub(crate) len: socklen_t,
+}
+
+impl AcceptSlot {
+    pub fn new() -> Self {
+        Self {
+            storage: unsafe { std::mem::zeroed() },
+            len: size_of::<sockaddr_storage>() as socklen_t,
+        }
+    }
+    // `None` until a successful accept has filled the slot, or for address
+    // families other than IPv4 and IPv6.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.storage.ss_family as i32 {
+            libc::AF_INET if self.len as usize >= size_of::<sockaddr_in>() => {
+                let sin = unsafe { &*(&self.storage as *const _ as *const sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 if self.len as usize >= size_of::<sockaddr_in6>() => {
+                let sin6 = unsafe { &*(&self.storage as *const _ as *const sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+// The C form of `addr`, e.g. for `Sqe::connect`.
+pub(crate) fn raw_socket_addr(addr: &SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage: sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            size_of::<sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            size_of::<sockaddr_in6>()
+        }
+    };
+    (storage, len as socklen_t)
+}
+
+impl Default for AcceptSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
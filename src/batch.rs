@@ -0,0 +1,403 @@
+use std::{
+    ffi::CStr,
+    io::{IoSlice, IoSliceMut},
+    os::unix::prelude::RawFd,
+    time::{Duration, Instant},
+};
+
+use libc::{iovec, sockaddr};
+
+use crate::{cqe::Cqe, sqe::Sqe, Result, Rring, RringError, UserData};
+
+// Prepares several operations and hands them to the kernel with a single
+// `io_uring_enter`. Every method acquires an SQE, preps it and tags it with
+// `user_data`, mirroring the matching `Sqe` method. Operations on a file
+// take their fd from `user_data.srcfd()`, so the fd operated on and the one
+// recorded for the completion can't disagree.
+pub struct SubmissionBatch<'a> {
+    ring: &'a Rring,
+    len: u32,
+}
+
+impl<'a> SubmissionBatch<'a> {
+    pub(crate) fn new(ring: &'a Rring) -> Self {
+        Self { ring, len: 0 }
+    }
+    fn push<T>(
+        &mut self,
+        user_data: UserData<T>,
+        prep: impl FnOnce(&mut Sqe, RawFd),
+    ) -> Result<&mut Self> {
+        self.push_tagged(user_data, prep)?;
+        Ok(self)
+    }
+    // `push`, returning the `user_data` value the completion will carry.
+    fn push_tagged<T>(
+        &mut self,
+        user_data: UserData<T>,
+        prep: impl FnOnce(&mut Sqe, RawFd),
+    ) -> Result<u64> {
+        let mut sqe = self.ring.get_sqe()?;
+        prep(&mut sqe, user_data.srcfd());
+        // Prepping resets `user_data`, so it has to be set afterwards.
+        let tag = sqe.set_user_data(user_data)?;
+        self.len += 1;
+        Ok(tag)
+    }
+    pub fn read<T>(
+        &mut self,
+        buf: &mut [u8],
+        nbytes: u32,
+        offset: i64,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.read(src, buf, nbytes, offset))
+    }
+    pub fn write<T>(
+        &mut self,
+        buf: &[u8],
+        nbytes: u32,
+        offset: i64,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.write(src, buf, nbytes, offset))
+    }
+    pub fn readv<T>(
+        &mut self,
+        bufs: &mut [IoSliceMut],
+        offset: i64,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.readv(src, bufs, offset))
+    }
+    pub fn writev<T>(
+        &mut self,
+        bufs: &[IoSlice],
+        offset: i64,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.writev(src, bufs, offset))
+    }
+    // `readv`/`writev` with the iovec array copied into `arena`, so only the
+    // buffers themselves have to outlive the operations, not `bufs`.
+    pub fn readv_in<T>(
+        &mut self,
+        arena: &mut IoVecArena,
+        bufs: &mut [IoSliceMut],
+        offset: i64,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        let iovecs = arena.alloc(bufs.iter_mut().map(|b| iovec {
+            iov_base: b.as_mut_ptr().cast(),
+            iov_len: b.len(),
+        }))?;
+        let tag = self.push_tagged(user_data, |sqe, src| sqe.readv_raw(src, iovecs, offset))?;
+        arena.pending.push(tag);
+        Ok(self)
+    }
+    pub fn writev_in<T>(
+        &mut self,
+        arena: &mut IoVecArena,
+        bufs: &[IoSlice],
+        offset: i64,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        let iovecs = arena.alloc(bufs.iter().map(|b| iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        }))?;
+        let tag = self.push_tagged(user_data, |sqe, src| sqe.writev_raw(src, iovecs, offset))?;
+        arena.pending.push(tag);
+        Ok(self)
+    }
+    pub fn fsync<T>(&mut self, fsync_flags: u32, user_data: UserData<T>) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.fsync(src, fsync_flags))
+    }
+    pub fn close<T>(&mut self, user_data: UserData<T>) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.close(src))
+    }
+    pub fn openat<T>(
+        &mut self,
+        dir: RawFd,
+        path: &CStr,
+        flags: i32,
+        mode: u32,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, _| sqe.openat(dir, path, flags, mode))
+    }
+    pub fn send<T>(
+        &mut self,
+        buf: &[u8],
+        len: usize,
+        flags: i32,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.send(src, buf, len, flags))
+    }
+    pub fn recv<T>(
+        &mut self,
+        buf: &mut [u8],
+        len: usize,
+        flags: i32,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.recv(src, buf, len, flags))
+    }
+    pub fn accept<T>(
+        &mut self,
+        addr: *mut sockaddr,
+        addrlen: &mut u32,
+        flags: i32,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.accept(src, addr, addrlen, flags))
+    }
+    pub fn connect<T>(
+        &mut self,
+        addr: *mut sockaddr,
+        addrlen: u32,
+        user_data: UserData<T>,
+    ) -> Result<&mut Self> {
+        self.push(user_data, |sqe, src| sqe.connect(src, addr, addrlen))
+    }
+    // Number of operations prepared since the last `submit`.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn submit(&mut self) -> Result<u32> {
+        self.len = 0;
+        self.ring.submit()
+    }
+}
+
+// Fixed-capacity storage for the iovec arrays of vectored operations, which
+// the kernel reads only once the operation runs. Arrays are bump allocated,
+// so preparing an operation never allocates, and the whole arena is recycled
+// with `reset` once every operation it backs has completed. The arena learns
+// about those completions through `complete`; dropping it before then leaks
+// the storage.
+pub struct IoVecArena {
+    iovecs: Box<[iovec]>,
+    used: usize,
+    // `user_data` of the operations prepared with storage from this arena
+    // whose completion hasn't been passed to `complete` yet.
+    pending: Vec<u64>,
+}
+
+impl IoVecArena {
+    pub fn new(capacity: usize) -> Self {
+        let empty = iovec {
+            iov_base: std::ptr::null_mut(),
+            iov_len: 0,
+        };
+        Self {
+            iovecs: vec![empty; capacity].into_boxed_slice(),
+            used: 0,
+            pending: Vec::new(),
+        }
+    }
+    // Free iovec slots left until the next `reset`.
+    pub fn remaining(&self) -> usize {
+        self.iovecs.len() - self.used
+    }
+    // Number of operations backed by this arena that haven't completed.
+    pub fn in_flight(&self) -> usize {
+        self.pending.len()
+    }
+    // Marks the operation behind `cqe` as done if its iovecs came from this
+    // arena, returning whether they did. Completions of `readv_in`/`writev_in`
+    // have to be passed here before their user data is taken with
+    // `Cqe::get_data`, or the arena never becomes resettable.
+    pub fn complete(&mut self, cqe: &Cqe) -> bool {
+        match self.pending.iter().position(|&tag| tag == cqe.user_data()) {
+            Some(i) => {
+                self.pending.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+    // Recycles the storage once every operation using it has completed,
+    // which is the only point at which none of it can still be read by the
+    // kernel. Operations on the ring that don't use the arena don't hold it
+    // up. Returns whether it did.
+    pub fn reset(&mut self) -> bool {
+        if !self.pending.is_empty() {
+            return false;
+        }
+        self.used = 0;
+        true
+    }
+    // Hands out an array that stays put until the next `reset`. The borrow
+    // only covers preparing the SQE; after that the kernel's copy of the
+    // pointer is kept valid by `reset` and `drop` refusing to free storage
+    // that a pending operation may still read.
+    fn alloc(&mut self, iovecs: impl ExactSizeIterator<Item = iovec>) -> Result<&[iovec]> {
+        let len = iovecs.len();
+        if len > self.remaining() {
+            return Err(RringError::WouldOverflow);
+        }
+        let start = self.used;
+        for (slot, iov) in self.iovecs[start..start + len].iter_mut().zip(iovecs) {
+            *slot = iov;
+        }
+        self.used += len;
+        Ok(&self.iovecs[start..start + len])
+    }
+}
+
+// Dropped with operations still pending, the storage is leaked rather than
+// freed under the kernel.
+impl Drop for IoVecArena {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            Box::leak(std::mem::take(&mut self.iovecs));
+        }
+    }
+}
+
+// Wraps a ring for workloads that prepare operations continuously and
+// submits on its own once `max_pending` SQEs have piled up, or when one is
+// prepared more than `max_delay` after the oldest pending one. There is no
+// timer behind `max_delay`: it's only checked in `prep`, so call `flush`
+// when going idle. Anything still pending is flushed on drop.
+pub struct AutoFlushRring<'a> {
+    ring: &'a Rring,
+    max_pending: u32,
+    max_delay: Option<Duration>,
+    pending: u32,
+    oldest: Option<Instant>,
+}
+
+impl<'a> AutoFlushRring<'a> {
+    pub fn new(ring: &'a Rring, max_pending: u32, max_delay: Option<Duration>) -> Self {
+        Self {
+            ring,
+            max_pending,
+            max_delay,
+            pending: 0,
+            oldest: None,
+        }
+    }
+    // Acquires an SQE and lets `prep` fill it in, flushing if that reaches a
+    // threshold.
+    pub fn prep(&mut self, prep: impl FnOnce(&mut Sqe)) -> Result<&mut Self> {
+        let mut sqe = self.ring.get_sqe()?;
+        prep(&mut sqe);
+        self.pending += 1;
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        let overdue = self
+            .max_delay
+            .is_some_and(|delay| oldest.elapsed() >= delay);
+        if self.pending >= self.max_pending || overdue {
+            self.flush()?;
+        }
+        Ok(self)
+    }
+    // Number of SQEs prepared since the last flush.
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+    pub fn flush(&mut self) -> Result<u32> {
+        self.pending = 0;
+        self.oldest = None;
+        self.ring.submit()
+    }
+}
+
+impl Drop for AutoFlushRring<'_> {
+    fn drop(&mut self) {
+        if self.pending > 0 {
+            let _ = self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSlice;
+
+    use super::IoVecArena;
+    use crate::{Identifier, Operation, Rring, SetupFlag, UserData};
+
+    #[test]
+    fn batch_submits_three_reads_at_once() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { libc::write(fds[1], b"abc".as_ptr().cast(), 3) }, 3);
+
+        let mut bufs = [[0u8; 1]; 3];
+        let mut batch = ring.batch();
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            let user_data = UserData::<()>::new(Operation::Read, Identifier(i as u128), fds[0]);
+            batch.read(buf, 1, -1, user_data).unwrap();
+        }
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.submit().unwrap(), 3);
+        assert!(batch.is_empty());
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let cqe = ring.wait().unwrap();
+            assert_eq!(cqe.get_result(), 1);
+            ids.push(cqe.get_data::<()>().unwrap().unwrap().id().0);
+            ring.seen(cqe);
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 1, 2]);
+        let mut read: Vec<u8> = bufs.concat();
+        read.sort_unstable();
+        assert_eq!(read, b"abc");
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn arena_reset_ignores_unrelated_operations() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // A read on an empty pipe stays in flight for the whole test.
+        let mut idle = [0u8; 1];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut idle, 1, 0);
+        ring.submit().unwrap();
+
+        let null = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY) };
+        assert!(null >= 0);
+        let mut arena = IoVecArena::new(4);
+        let bufs = [IoSlice::new(b"ab"), IoSlice::new(b"cd")];
+        let user_data = UserData::<()>::new(Operation::Writev, Identifier(1), null);
+        let mut batch = ring.batch();
+        batch.writev_in(&mut arena, &bufs, 0, user_data).unwrap();
+        batch.submit().unwrap();
+        assert!(!arena.reset());
+
+        let cqe = ring.wait().unwrap();
+        assert!(arena.complete(&cqe));
+        assert_eq!(cqe.get_result(), 4);
+        drop(cqe.get_data::<()>());
+        ring.seen(cqe);
+        assert!(ring.stats().in_flight > 0);
+        assert!(arena.reset());
+        assert_eq!(arena.remaining(), 4);
+
+        // Closing the write end lets the idle read complete.
+        unsafe { libc::close(fds[1]) };
+        let cqe = ring.wait().unwrap();
+        assert!(!arena.complete(&cqe));
+        assert_eq!(cqe.get_result(), 0);
+        ring.seen(cqe);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(null);
+        }
+    }
+}
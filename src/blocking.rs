@@ -0,0 +1,524 @@
+// Helpers that submit an operation (or chain) and wait for it on the calling
+// thread. They expect to be the only users of the ring while they run, since
+// any other completion reaped in the meantime would be consumed here.
+use std::{
+    ffi::CStr,
+    io,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    os::unix::prelude::RawFd,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use libc::statx;
+use uring_sys::IORING_FSYNC_DATASYNC;
+
+use crate::{
+    accept::raw_socket_addr,
+    cqe::Cqe,
+    sqe::{check_provided_buffers, Sqe},
+    tag::{self, TagKind},
+    Metadata, Result, Rring, RringError, SqeFlag,
+};
+
+// Chain tags carry the chain's number above the position of the SQE in it.
+const CHAIN_SHIFT: u32 = 8;
+static NEXT_CHAIN: AtomicU64 = AtomicU64::new(0);
+
+// Tags each of `sqes` with its position in a new chain, so `wait_chain` can
+// match the completions to them without relying on the order they arrive in.
+fn tag_chain(sqes: &mut [&mut Sqe]) -> u64 {
+    let chain = NEXT_CHAIN.fetch_add(1, Ordering::Relaxed) & (tag::PAYLOAD_MASK >> CHAIN_SHIFT);
+    for (i, sqe) in sqes.iter_mut().enumerate() {
+        sqe.set_tag(TagKind::Chain, chain << CHAIN_SHIFT | i as u64);
+    }
+    chain
+}
+
+impl Rring {
+    // Submits the SQE prepared by `prep` and returns its result.
+    pub(crate) fn run_one(&self, prep: impl FnOnce(&mut Sqe)) -> Result<i32> {
+        let mut sqe = self.get_sqe()?;
+        prep(&mut sqe);
+        self.submit()?;
+        let cqe = self.wait_cqe()?;
+        let res = cqe.get_result();
+        self.seen(cqe);
+        if res < 0 {
+            Err(RringError::from_errno(-res))
+        } else {
+            Ok(res)
+        }
+    }
+    // Waits for the results of the `N` SQEs tagged by `tag_chain`. Once all of
+    // them are in, those leading the CQ are released; any queued behind
+    // another operation's completion are left for the caller to reap, and
+    // carry no user data.
+    fn wait_chain<const N: usize>(&self, chain: u64) -> Result<[i32; N]> {
+        let position = |cqe: &Cqe| match tag::decode(cqe.user_data()) {
+            Some((TagKind::Chain, payload)) if payload >> CHAIN_SHIFT == chain => {
+                Some((payload & ((1 << CHAIN_SHIFT) - 1)) as usize)
+            }
+            _ => None,
+        };
+        let capacity = self.cqe_buf.borrow().len();
+        let mut ready = Vec::new();
+        loop {
+            ready.clear();
+            let n = self.peek_batch(&mut ready, capacity) as usize;
+            let mut results = [None; N];
+            for cqe in &ready {
+                if let Some(i) = position(cqe) {
+                    results[i] = Some(cqe.get_result());
+                }
+            }
+            if results.iter().all(Option::is_some) {
+                let ours = ready
+                    .iter()
+                    .take_while(|cqe| position(cqe).is_some())
+                    .count();
+                ready.clear();
+                self.advance(ours as u32);
+                return Ok(results.map(Option::unwrap));
+            }
+            // A full CQ can't take the rest of the chain until it's reaped.
+            if n == capacity {
+                return Err(RringError::QueueFull);
+            }
+            self.submit_and_wait(n as u32 + 1)?;
+        }
+    }
+    // A single read of up to `buf.len()` bytes at `offset` (-1 for the file
+    // position), returning how many were read. Like `pread(2)` the count may
+    // be short, and is 0 at end of file.
+    pub fn pread(&self, fd: RawFd, buf: &mut [u8], offset: i64) -> Result<usize> {
+        let n = buf.len().min(u32::MAX as usize) as u32;
+        let res = self.run_one(|sqe| sqe.read(fd, buf, n, offset))?;
+        Ok(res as usize)
+    }
+    // The write counterpart of `pread`, with the same partial-count rules.
+    pub fn pwrite(&self, fd: RawFd, buf: &[u8], offset: i64) -> Result<usize> {
+        let n = buf.len().min(u32::MAX as usize) as u32;
+        let res = self.run_one(|sqe| sqe.write(fd, buf, n, offset))?;
+        Ok(res as usize)
+    }
+    // Runs a `Sqe::statx` of `path` relative to `dir` (e.g. `AT_FDCWD`) asking
+    // for everything, with `flags` such as `AT_SYMLINK_NOFOLLOW`.
+    pub fn statx_metadata(&self, dir: RawFd, path: &CStr, flags: i32) -> Result<Metadata> {
+        let mut buf = MaybeUninit::<statx>::zeroed();
+        self.run_one(|sqe| sqe.statx(dir, path, flags, libc::STATX_ALL, buf.as_mut_ptr()))?;
+        Ok(Metadata::from_statx(unsafe { buf.assume_init_ref() }))
+    }
+    // Opens `path`, reads up to `buf.len()` bytes at `offset` and closes it
+    // again in a single linked submission, returning the count read. The file
+    // only ever lives in the fixed file slot `file_index`, which has to be
+    // free in a registered table (see `register_files_sparse`); the slot is
+    // known up front so the read can be prepared before the open has run.
+    pub fn open_then_read(
+        &self,
+        path: &CStr,
+        offset: i64,
+        buf: &mut [u8],
+        file_index: u32,
+    ) -> Result<usize> {
+        let n = buf.len().min(u32::MAX as usize) as u32;
+        // The chain must not be left half prepared in the SQ.
+        if self.sq_space_left() < 3 {
+            return Err(RringError::QueueFull);
+        }
+        let mut open = self.get_sqe()?;
+        let mut read = self.get_sqe()?;
+        let mut close = self.get_sqe()?;
+        open.openat_direct(libc::AT_FDCWD, path, libc::O_RDONLY, 0, file_index);
+        open.link();
+        read.read_fixed_file(file_index, buf, n, offset);
+        // A short read, e.g. at end of file, breaks a plain link and would
+        // cancel the close, leaving the slot occupied.
+        read.add_flags(SqeFlag::IO_HARDLINK);
+        close.close_direct(file_index);
+        self.submit_robust()?;
+        // Linked operations complete in order.
+        let mut results = [0; 3];
+        for res in results.iter_mut() {
+            let cqe = self.wait_cqe()?;
+            *res = cqe.get_result();
+            self.seen(cqe);
+        }
+        // A failed open cancels the rest of the chain, so report its error
+        // rather than the ECANCELED of the read. Once the open succeeded, a
+        // failed close means the slot is still taken.
+        match results {
+            [open, _, _] if open < 0 => Err(RringError::from_errno(-open)),
+            [_, read, _] if read < 0 => Err(RringError::from_errno(-read)),
+            [_, _, close] if close < 0 => Err(RringError::from_errno(-close)),
+            [_, read, _] => Ok(read as usize),
+        }
+    }
+    // Provides `nr` buffers like `Sqe::provide_buffers`, resubmitting
+    // whatever the kernel didn't take until it takes none, and returns the
+    // number added. That is short of `nr` once the group is full, as far as
+    // the kernel lets on: kernels that report 0 rather than a count on
+    // success (see `Cqe::provided_buffers`) also do so when only some of
+    // the buffers fit, so there a provision that filled the group is counted
+    // in full, and only the next one into the group fails with EOVERFLOW.
+    // Removing the group's buffers is the only way to count them exactly.
+    pub fn provide_buffers_all(
+        &self,
+        bufs: &mut [u8],
+        len: i32,
+        nr: i32,
+        bgid: u16,
+        bid: i32,
+    ) -> Result<u32> {
+        check_provided_buffers(bufs.len(), len, nr, bid)?;
+        let mut added = 0;
+        while (added as i32) < nr {
+            let rest = nr - added as i32;
+            let bufs = &mut bufs[added as usize * len as usize..];
+            let res = self.run_one(|sqe| {
+                sqe.provide_buffers(bufs, len, rest, bgid, bid + added as i32)
+                    .expect("the remainder of a checked request is valid");
+            });
+            match res {
+                Ok(n) => added += if n == 0 { rest as u32 } else { n as u32 },
+                // Nothing more fits in the group.
+                Err(err) if added > 0 && err.raw_os_error() == Some(libc::EOVERFLOW) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(added)
+    }
+    // Connects `socket` to `addr` and sends `buf` in a single linked
+    // submission, returning the count sent. The send only runs once the
+    // connect has succeeded; if it fails, the send is cancelled and the
+    // connect's error is returned.
+    pub fn connect_then_send(
+        &self,
+        socket: RawFd,
+        addr: &SocketAddr,
+        buf: &[u8],
+        flags: i32,
+    ) -> Result<usize> {
+        let (mut storage, len) = raw_socket_addr(addr);
+        if self.sq_space_left() < 2 {
+            return Err(RringError::QueueFull);
+        }
+        let mut connect = self.get_sqe()?;
+        let mut send = self.get_sqe()?;
+        connect.connect(
+            socket,
+            (&mut storage as *mut libc::sockaddr_storage).cast(),
+            len,
+        );
+        connect.link();
+        send.send(socket, buf, buf.len(), flags);
+        let chain = tag_chain(&mut [&mut connect, &mut send]);
+        self.submit_robust()?;
+        match self.wait_chain(chain)? {
+            [connect, _] if connect < 0 => Err(RringError::from_errno(-connect)),
+            [_, sent] if sent < 0 => Err(RringError::from_errno(-sent)),
+            [_, sent] => Ok(sent as usize),
+        }
+    }
+    // Appends `buf` to `fd` and datasyncs it in a single linked submission,
+    // returning the count written once both have completed, so the record is
+    // durable by the time this returns. A failed or short write cancels the
+    // fsync and is reported as the error or the short count.
+    pub fn durable_append(&self, fd: RawFd, buf: &[u8]) -> Result<usize> {
+        let n = buf.len().min(u32::MAX as usize) as u32;
+        if self.sq_space_left() < 2 {
+            return Err(RringError::QueueFull);
+        }
+        let mut write = self.get_sqe()?;
+        let mut fsync = self.get_sqe()?;
+        write.write_append(fd, buf, n);
+        write.link();
+        fsync.fsync(fd, IORING_FSYNC_DATASYNC);
+        self.submit_robust()?;
+        let mut results = [0; 2];
+        for res in results.iter_mut() {
+            let cqe = self.wait_cqe()?;
+            *res = cqe.get_result();
+            self.seen(cqe);
+        }
+        match results {
+            [written, _] if written < 0 => Err(RringError::from_errno(-written)),
+            // A short write breaks the link, leaving the data unsynced.
+            [written, _] if (written as u32) < n => Ok(written as usize),
+            [_, synced] if synced < 0 => Err(RringError::from_errno(-synced)),
+            [written, _] => Ok(written as usize),
+        }
+    }
+    // Writes `buf` at `offset` and reads the region back in a single linked
+    // submission, failing with `InvalidData` if what comes back differs. A
+    // short write stops the chain and fails with `WriteZero`. Note the read
+    // may well be served from the page cache, so this catches corruption on
+    // the way there rather than on the device; see `Sqe::write_direct` for
+    // the latter.
+    pub fn write_verified(&self, fd: RawFd, buf: &[u8], offset: i64) -> Result<usize> {
+        if offset < 0 {
+            return Err(RringError::invalid_input(
+                "a verified write needs an explicit offset",
+            ));
+        }
+        let n = buf.len().min(u32::MAX as usize) as u32;
+        let mut back = vec![0; n as usize];
+        if self.sq_space_left() < 2 {
+            return Err(RringError::QueueFull);
+        }
+        let mut write = self.get_sqe()?;
+        let mut read = self.get_sqe()?;
+        write.write(fd, buf, n, offset);
+        write.link();
+        read.read(fd, &mut back, n, offset);
+        let chain = tag_chain(&mut [&mut write, &mut read]);
+        self.submit_robust()?;
+        match self.wait_chain(chain)? {
+            [written, _] if written < 0 => Err(RringError::from_errno(-written)),
+            [written, _] if (written as u32) < n => Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short write of {} out of {} bytes", written, n),
+            )
+            .into()),
+            [_, read] if read < 0 => Err(RringError::from_errno(-read)),
+            [_, read] if back[..read as usize] != buf[..n as usize] => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the data read back differs from what was written",
+            )
+            .into()),
+            [written, _] => Ok(written as usize),
+        }
+    }
+    // Moves `total` bytes from `in_fd` to `out_fd`, issuing as many splices as
+    // it takes since a single one may move less than asked for. Offsets of -1
+    // use (and advance) the file position, as for `Sqe::splice`. Returns the
+    // number of bytes moved, which is short of `total` only at end of input.
+    pub fn splice_all(
+        &self,
+        in_fd: RawFd,
+        mut in_offset: i64,
+        out_fd: RawFd,
+        mut out_offset: i64,
+        total: usize,
+        flags: u32,
+    ) -> Result<usize> {
+        let mut moved = 0;
+        while moved < total {
+            let n = (total - moved).min(u32::MAX as usize) as u32;
+            let res = self.run_one(|sqe| {
+                sqe.splice(in_fd, in_offset, out_fd, out_offset, n, flags);
+            })?;
+            if res == 0 {
+                break;
+            }
+            moved += res as usize;
+            if in_offset >= 0 {
+                in_offset += res as i64;
+            }
+            if out_offset >= 0 {
+                out_offset += res as i64;
+            }
+        }
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, fs, io, os::unix::ffi::OsStrExt, path::PathBuf};
+
+    use crate::{Rring, RringError, SetupFlag};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rring-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn open_then_read_short_read_closes_slot() {
+        let path = temp_path("open-then-read");
+        fs::write(&path, b"hello").unwrap();
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        ring.register_files_sparse(1).unwrap();
+        let mut buf = [0u8; 64];
+        let n = ring.open_then_read(&cpath, 0, &mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        // The close ran despite the short read, so the slot is empty again.
+        let res = ring.run_one(|sqe| sqe.close_direct(0));
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EBADF));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn durable_append_round_trip() {
+        let path = temp_path("durable-append");
+        fs::write(&path, b"log:").unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&file);
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        assert_eq!(ring.durable_append(fd, b" one").unwrap(), 4);
+        assert_eq!(ring.durable_append(fd, b" two").unwrap(), 4);
+        assert_eq!(ring.stats().in_flight, 0);
+        assert_eq!(fs::read(&path).unwrap(), b"log: one two");
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_verified_reads_back_what_it_wrote() {
+        let path = temp_path("write-verified");
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&file);
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        assert_eq!(ring.write_verified(fd, b"checked", 3).unwrap(), 7);
+        assert!(ring.peek().is_none());
+        // A completion already waiting is neither mistaken for the chain's
+        // nor consumed, and the chain's then queue up behind it.
+        ring.get_sqe().unwrap().nop();
+        ring.submit().unwrap();
+        assert_eq!(ring.write_verified(fd, b"CHECKED", 3).unwrap(), 7);
+        let reaped = ring.drain_into(|cqe| {
+            assert!(cqe.get_result() >= 0);
+            assert!(cqe.get_data::<()>().unwrap().is_none());
+        });
+        assert_eq!(reaped, 3);
+        assert_eq!(fs::read(&path).unwrap(), b"\0\0\0CHECKED");
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_verified_detects_a_short_write() {
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) },
+            0
+        );
+        // A non-blocking socket takes what fits in its buffer and no more.
+        assert_eq!(
+            unsafe { libc::fcntl(fds[0], libc::F_SETFL, libc::O_NONBLOCK) },
+            0
+        );
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let buf = vec![7u8; 4 << 20];
+        let err = ring.write_verified(fds[0], &buf, 0).unwrap_err();
+        assert!(
+            matches!(&err, RringError::Io(e) if e.kind() == io::ErrorKind::WriteZero),
+            "{}",
+            err
+        );
+        // Both completions were reaped.
+        assert!(ring.peek().is_none());
+        assert_eq!(ring.stats().in_flight, 0);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn connect_then_send_delivers_the_greeting() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(socket >= 0);
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        assert_eq!(
+            ring.connect_then_send(socket, &addr, b"hello", 0).unwrap(),
+            5
+        );
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 5];
+        io::Read::read_exact(&mut stream, &mut greeting).unwrap();
+        assert_eq!(&greeting, b"hello");
+        assert!(ring.peek().is_none());
+        unsafe { libc::close(socket) };
+    }
+
+    #[test]
+    fn connect_then_send_cancels_the_send_when_connect_fails() {
+        // A port nothing listens on, freed again right after binding.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(socket >= 0);
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let err = ring
+            .connect_then_send(socket, &addr, b"hello", 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ECONNREFUSED));
+        // The cancelled send's completion was reaped along with the connect's.
+        assert!(ring.peek().is_none());
+        assert_eq!(ring.stats().in_flight, 0);
+        unsafe { libc::close(socket) };
+    }
+
+    #[test]
+    fn provide_buffers_all_into_a_nearly_full_group() {
+        // A group holds at most 65535 buffers, so of 1000 more only 535 fit.
+        const HELD: i32 = 65000;
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut bufs = vec![0u8; HELD as usize + 1000];
+        let (held, extra) = bufs.split_at_mut(HELD as usize);
+        assert_eq!(
+            ring.provide_buffers_all(held, 1, HELD, 3, 0).unwrap(),
+            HELD as u32
+        );
+        let added = ring.provide_buffers_all(extra, 1, 1000, 3, 0).unwrap();
+        // Either the kernel reported the partial count, or it reported 0
+        // and the rest shows up as the group being full.
+        if added == 1000 {
+            let err = ring.provide_buffers_all(extra, 1, 1, 3, 0).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EOVERFLOW));
+        } else {
+            assert_eq!(added, 535);
+        }
+        let removed = ring.run_one(|sqe| sqe.remove_buffers(u16::MAX as i32, 3));
+        assert_eq!(removed.unwrap(), u16::MAX as i32);
+    }
+
+    #[test]
+    fn splice_all_moves_more_than_a_pipe_holds() {
+        const TOTAL: usize = 1 << 20;
+        let path = temp_path("splice-all");
+        let data: Vec<u8> = (0..TOTAL).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let reader = fds[0];
+        let drain = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = unsafe { libc::read(reader, buf.as_mut_ptr().cast(), buf.len()) };
+                assert!(n >= 0);
+                if n == 0 {
+                    return out;
+                }
+                out.extend_from_slice(&buf[..n as usize]);
+            }
+        });
+
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&file);
+        assert_eq!(ring.splice_all(fd, 0, fds[1], -1, TOTAL, 0).unwrap(), TOTAL);
+        // The pipe holds 64 KiB by default, so no single splice did it.
+        assert!(ring.stats().submitted >= (TOTAL / (64 << 10)) as u64);
+        unsafe { libc::close(fds[1]) };
+        assert!(drain.join().unwrap() == data);
+
+        unsafe { libc::close(fds[0]) };
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+}
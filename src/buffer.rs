@@ -0,0 +1,611 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    io,
+    mem::size_of,
+    ptr::{addr_of_mut, NonNull},
+    slice,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use uring_sys::*;
+
+use crate::{
+    cqe::Cqe,
+    recv_buf::RecvSlot,
+    sys::{
+        io_uring_buf, io_uring_buf_reg, IORING_CQE_BUFFER_SHIFT, IORING_CQE_F_BUFFER,
+        IORING_REGISTER_PBUF_RING, IORING_UNREGISTER_PBUF_RING, IOU_PBUF_RING_INC,
+    },
+    tag::{self, TagKind},
+    Result, Rring, RringError,
+};
+
+// A buffer occupying one slot of the ring's fixed buffer table, for use with
+// the `*_fixed` operations. The handle borrows the ring, so it can't outlive
+// it, and empties its slot when dropped, leaving the rest of the table alone.
+pub struct RegisteredBuffer<'ring> {
+    ring: &'ring Rring,
+    ptr: NonNull<u8>,
+    len: usize,
+    index: u16,
+}
+
+impl<'ring> RegisteredBuffer<'ring> {
+    // Maps `size` anonymous bytes with the extra mmap `flags` and puts them in
+    // slot `index` of a sparse table. With MAP_HUGETLB `size` has to be a
+    // multiple of the default huge page size, and the mapping fails with
+    // ENOMEM when the system has no huge pages reserved (see
+    // `vm.nr_hugepages`).
+    pub(crate) fn map(ring: &'ring Rring, index: u16, size: usize, flags: i32) -> Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let iov = libc::iovec {
+            iov_base: ptr,
+            iov_len: size,
+        };
+        if let Err(err) = ring.update_iovecs(index as u32, &[iov]) {
+            unsafe {
+                libc::munmap(ptr, size);
+            }
+            return Err(err);
+        }
+        Ok(Self {
+            ring,
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len: size,
+            index,
+        })
+    }
+    // Index to pass as `buf_index` to the `*_fixed` operations.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// The kernel keeps the pages pinned until operations still using the slot
+// have completed, so unmapping right away is fine.
+impl Drop for RegisteredBuffer<'_> {
+    fn drop(&mut self) {
+        let empty = libc::iovec {
+            iov_base: std::ptr::null_mut(),
+            iov_len: 0,
+        };
+        let _ = self.ring.update_iovecs(self.index as u32, &[empty]);
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
+
+// A fixed buffer table made of buffers the handle owns, so they can't be
+// freed or moved while the kernel has them pinned. Buffer `i` of the table is
+// the `i`th of the registered `Vec`s. The table is unregistered when the
+// handle is dropped, or with `unregister`, which hands the buffers back.
+pub struct RegisteredBuffers<'ring> {
+    ring: &'ring Rring,
+    bufs: Vec<Vec<u8>>,
+}
+
+impl<'ring> RegisteredBuffers<'ring> {
+    pub(crate) fn new(ring: &'ring Rring, mut bufs: Vec<Vec<u8>>) -> Result<Self> {
+        let iovecs: Vec<_> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        ring.register_iovecs(&iovecs)?;
+        Ok(Self { ring, bufs })
+    }
+    // Number of buffers in the table.
+    pub fn len(&self) -> usize {
+        self.bufs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bufs.is_empty()
+    }
+    pub fn buffer(&self, index: u16) -> &[u8] {
+        &self.bufs[index as usize]
+    }
+    pub fn buffer_mut(&mut self, index: u16) -> &mut [u8] {
+        &mut self.bufs[index as usize]
+    }
+    pub fn unregister(mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.bufs)
+    }
+}
+
+impl Drop for RegisteredBuffers<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            io_uring_unregister_buffers(self.ring._inner);
+        }
+    }
+}
+
+// Compile-time checks that a `BufRing` keeps its ring alive. Doctests are the
+// only place `compile_fail` can be expressed, hence the doc comments.
+/// ```compile_fail,E0505
+/// let ring = rring::Rring::new(8, rring::SetupFlag::empty()).unwrap();
+/// let bufs = ring.register_buf_ring(8, 64, 0).unwrap();
+/// drop(ring);
+/// drop(bufs);
+/// ```
+///
+/// ```
+/// let ring = rring::Rring::new(8, rring::SetupFlag::empty()).unwrap();
+/// let bufs = ring.register_buf_ring(8, 64, 0).unwrap();
+/// drop(bufs);
+/// drop(ring);
+/// ```
+#[cfg(doctest)]
+pub struct BufRingBorrowsRing;
+
+// A provided buffer ring (5.19+) together with the buffers it hands out.
+// Operations submitted with buffer selection on group `bgid` take a free
+// buffer from the ring and report its id in the completion (see
+// `Cqe::buffer_id`); the buffer goes back to the kernel with `recycle`.
+// In incremental mode (6.12+) the kernel only uses up as much of a buffer as
+// each completion needs and keeps handing out the rest, so the consumed
+// part has to be worked out with `resolve`.
+pub struct BufRing<'ring> {
+    pub(crate) ring: &'ring Rring,
+    entries: NonNull<io_uring_buf>,
+    nr_entries: u16,
+    bgid: u16,
+    // A `Cell` so that a `RecvGuard` can recycle through a shared borrow.
+    tail: Cell<u16>,
+    buf_size: usize,
+    bufs: Box<[u8]>,
+    // How far into each buffer the kernel has got, in incremental mode.
+    offsets: Option<Box<[Cell<usize>]>>,
+    // The `recv_buf` calls in flight, see `complete`.
+    pub(crate) recvs: RefCell<HashMap<u64, RecvSlot>>,
+    pub(crate) next_recv: Cell<u64>,
+}
+
+impl<'ring> BufRing<'ring> {
+    // `nr_entries` has to be a power of two no larger than 32768: the kernel
+    // masks ring positions with `nr_entries - 1`, so any other count would
+    // make the kernel and the ring disagree on which buffer was used.
+    pub(crate) fn new(
+        ring: &'ring Rring,
+        nr_entries: u32,
+        buf_size: usize,
+        bgid: u16,
+        incremental: bool,
+    ) -> Result<Self> {
+        if !nr_entries.is_power_of_two() || nr_entries > 1 << 15 {
+            return Err(RringError::invalid_input(format!(
+                "buffer ring entries must be a power of two up to 32768, got {}",
+                nr_entries
+            )));
+        }
+        if buf_size == 0 || buf_size > u32::MAX as usize {
+            return Err(RringError::invalid_input(format!(
+                "invalid provided buffer size {}",
+                buf_size
+            )));
+        }
+        let ring_len = nr_entries as usize * size_of::<io_uring_buf>();
+        // The ring has to be page aligned, which an anonymous mapping is.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                ring_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut reg = io_uring_buf_reg {
+            ring_addr: ptr as u64,
+            ring_entries: nr_entries,
+            bgid,
+            flags: if incremental { IOU_PBUF_RING_INC } else { 0 },
+            ..Default::default()
+        };
+        if let Err(err) = ring.register_raw(IORING_REGISTER_PBUF_RING, addr_of_mut!(reg).cast(), 1)
+        {
+            unsafe {
+                libc::munmap(ptr, ring_len);
+            }
+            return Err(err);
+        }
+        let buf_ring = Self {
+            ring,
+            entries: NonNull::new(ptr.cast()).unwrap(),
+            nr_entries: nr_entries as u16,
+            bgid,
+            tail: Cell::new(0),
+            buf_size,
+            bufs: vec![0; nr_entries as usize * buf_size].into_boxed_slice(),
+            offsets: incremental.then(|| (0..nr_entries).map(|_| Cell::new(0)).collect()),
+            recvs: RefCell::new(HashMap::new()),
+            next_recv: Cell::new(0),
+        };
+        for bid in 0..nr_entries as u16 {
+            buf_ring.push(bid);
+        }
+        buf_ring.publish();
+        Ok(buf_ring)
+    }
+    pub fn bgid(&self) -> u16 {
+        self.bgid
+    }
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+    // The buffer with id `bid`, e.g. to read what a completion put there.
+    pub fn buffer(&self, bid: u16) -> &[u8] {
+        let start = bid as usize * self.buf_size;
+        &self.bufs[start..start + self.buf_size]
+    }
+    // The buffer id, offset and length of the data a completion on this ring
+    // received, or `None` if it carries no buffer. In incremental mode this
+    // also tracks how much of the buffer is used up, so it has to be called
+    // exactly once for every such completion, in order.
+    pub fn resolve(&self, cqe: &Cqe) -> Option<(u16, usize, usize)> {
+        let flags = cqe.flags();
+        let res = cqe.get_result();
+        if flags & IORING_CQE_F_BUFFER == 0 || res < 0 {
+            return None;
+        }
+        let bid = (flags >> IORING_CQE_BUFFER_SHIFT) as u16;
+        let len = res as usize;
+        let offset = match &self.offsets {
+            Some(offsets) => {
+                let offset = offsets.get(bid as usize)?;
+                let start = offset.get();
+                // Without BUF_MORE the kernel is done with the buffer and the
+                // next time it hands it out is after a `recycle`.
+                offset.set(if cqe.buffer_more() { start + len } else { 0 });
+                start
+            }
+            None => 0,
+        };
+        Some((bid, offset, len))
+    }
+    // Hands the buffer `bid` back to the kernel once its contents have been
+    // consumed.
+    pub fn recycle(&mut self, bid: u16) {
+        self.recycle_shared(bid);
+    }
+    pub(crate) fn recycle_shared(&self, bid: u16) {
+        assert!(bid < self.nr_entries, "buffer id {} out of range", bid);
+        if let Some(offsets) = &self.offsets {
+            offsets[bid as usize].set(0);
+        }
+        self.push(bid);
+        self.publish();
+    }
+    fn push(&self, bid: u16) {
+        let mask = self.nr_entries - 1;
+        let addr = self.bufs[bid as usize * self.buf_size..].as_ptr();
+        let tail = self.tail.get();
+        unsafe {
+            let entry = &mut *self.entries.as_ptr().add((tail & mask) as usize);
+            entry.addr = addr as u64;
+            entry.len = self.buf_size as u32;
+            entry.bid = bid;
+        }
+        self.tail.set(tail.wrapping_add(1));
+    }
+    // Makes the entries pushed so far visible to the kernel.
+    fn publish(&self) {
+        unsafe {
+            let tail = addr_of_mut!((*self.entries.as_ptr()).resv) as *const AtomicU16;
+            (*tail).store(self.tail.get(), Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufRing<'_> {
+    fn drop(&mut self) {
+        let mut reg = io_uring_buf_reg {
+            bgid: self.bgid,
+            ..Default::default()
+        };
+        let _ = self
+            .ring
+            .register_raw(IORING_UNREGISTER_PBUF_RING, addr_of_mut!(reg).cast(), 1);
+        unsafe {
+            libc::munmap(
+                self.entries.as_ptr().cast(),
+                self.nr_entries as usize * size_of::<io_uring_buf>(),
+            );
+        }
+    }
+}
+
+// A group of buffers handed to the kernel with `Sqe::provide_buffers`, the
+// pre-5.19 alternative to a `BufRing`. The pool owns the memory, so the
+// buffers stay valid for as long as the kernel may pick them. As with a
+// `BufRing`, the chosen buffer id comes back in the completion, and the
+// buffer is handed back with `recycle`.
+pub struct BufferPool<'ring> {
+    ring: &'ring Rring,
+    nr: u16,
+    bgid: u16,
+    buf_size: usize,
+    bufs: Box<[u8]>,
+}
+
+impl<'ring> BufferPool<'ring> {
+    // Provides the buffers through `provide_buffers_all`, so the same caveat
+    // about other completions applies.
+    pub(crate) fn new(ring: &'ring Rring, nr: u16, buf_size: usize, bgid: u16) -> Result<Self> {
+        if nr == 0 || buf_size == 0 || buf_size > i32::MAX as usize {
+            return Err(RringError::invalid_input(format!(
+                "invalid buffer pool of {} buffers of {} bytes",
+                nr, buf_size
+            )));
+        }
+        let mut pool = Self {
+            ring,
+            nr,
+            bgid,
+            buf_size,
+            bufs: vec![0; nr as usize * buf_size].into_boxed_slice(),
+        };
+        let added =
+            ring.provide_buffers_all(&mut pool.bufs, buf_size as i32, nr as i32, bgid, 0)?;
+        if added < nr as u32 {
+            // Dropping the pool takes back the ones that did go in.
+            return Err(RringError::WouldOverflow);
+        }
+        Ok(pool)
+    }
+    pub fn bgid(&self) -> u16 {
+        self.bgid
+    }
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+    // The buffer with id `bid`, e.g. to read what a completion put there.
+    pub fn buffer(&self, bid: u16) -> &[u8] {
+        let start = bid as usize * self.buf_size;
+        &self.bufs[start..start + self.buf_size]
+    }
+    // Queues an SQE that hands the buffer `bid` back to the kernel; it takes
+    // effect with the next submit. Its completion carries no user data.
+    pub fn recycle(&mut self, bid: u16) -> Result<()> {
+        assert!(bid < self.nr, "buffer id {} out of range", bid);
+        let start = bid as usize * self.buf_size;
+        let buf = &mut self.bufs[start..start + self.buf_size];
+        self.ring
+            .get_sqe()?
+            .provide_buffers(buf, self.buf_size as i32, 1, self.bgid, bid as i32)
+    }
+}
+
+impl BufferPool<'_> {
+    // Submits the removal of the group and blocks until its completion is in
+    // the CQ. The completion is left there for the caller to reap, as other
+    // completions may be ahead of it; it carries no user data.
+    fn remove_and_wait(&self) -> Result<()> {
+        let mut sqe = self.ring.get_sqe()?;
+        sqe.remove_buffers(self.nr as i32, self.bgid);
+        sqe.set_tag(TagKind::PoolRemoval, self.bgid as u64);
+        let removal = tag::encode(TagKind::PoolRemoval, self.bgid as u64);
+        self.ring.submit_robust()?;
+        let capacity = self.ring.cqe_buf.borrow().len();
+        let mut ready = Vec::new();
+        loop {
+            ready.clear();
+            let n = self.ring.peek_batch(&mut ready, capacity) as usize;
+            if ready.iter().any(|cqe| cqe.user_data() == removal) {
+                return Ok(());
+            }
+            // A full CQ can't take the removal's completion until it's
+            // reaped, so there's nothing to wait for.
+            if n == capacity {
+                return Err(RringError::QueueFull);
+            }
+            self.ring.submit_and_wait(n as u32 + 1)?;
+        }
+    }
+}
+
+impl Drop for BufferPool<'_> {
+    // The memory only goes once the kernel has confirmed that the buffers
+    // left the group, so an in-flight recv can't pick one afterwards. If the
+    // removal can't be submitted or waited for, the memory is leaked instead.
+    fn drop(&mut self) {
+        if self.remove_and_wait().is_err() {
+            std::mem::forget(std::mem::take(&mut self.bufs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegisteredBuffer;
+    use crate::{tag, Rring, RringError, SetupFlag};
+
+    #[test]
+    fn dropping_a_pool_waits_for_the_removal() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let pool = ring.provide_buffer_pool(4, 16, 5).unwrap();
+        // An unrelated completion already waiting doesn't count as the removal.
+        ring.get_sqe().unwrap().nop();
+        ring.submit_and_wait(1).unwrap();
+        drop(pool);
+
+        let mut ready = Vec::new();
+        assert_eq!(ring.peek_batch(&mut ready, 8), 2);
+        assert!(tag::decode(ready[0].user_data()).is_none());
+        assert!(tag::is_tag(ready[1].user_data()));
+        assert_eq!(ready[1].get_result(), 4);
+        assert!(ready[1].get_data::<()>().unwrap().is_none());
+        ring.advance(2);
+
+        // The group is gone, so a recv selecting from it finds no buffer.
+        let mut fds = [0; 2];
+        let ret =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        ring.get_sqe().unwrap().recv_buf_select(fds[0], 5, 16, 0);
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.get_result(), -libc::ENOBUFS);
+        ring.seen(cqe);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn buf_ring_entries_must_be_a_power_of_two() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        match ring.register_buf_ring(100, 64, 0) {
+            Err(RringError::Io(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+                assert!(err.to_string().contains("power of two"));
+            }
+            _ => panic!("100 entries were accepted"),
+        }
+        assert!(ring.register_buf_ring(1 << 16, 64, 1).is_err());
+        let bufs = ring.register_buf_ring(128, 64, 2).unwrap();
+        assert_eq!(bufs.bgid(), 2);
+    }
+
+    fn write_fixed(ring: &Rring, fd: i32, buf: &RegisteredBuffer) -> i32 {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.write_registered(fd, buf, 4, 0).unwrap();
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        let res = cqe.get_result();
+        ring.seen(cqe);
+        res
+    }
+
+    #[test]
+    fn registered_buffer_releases_only_its_slot() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        ring.register_buffers_sparse(4).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let page = 4096;
+        let mut first = RegisteredBuffer::map(&ring, 2, page, 0).unwrap();
+        let second = RegisteredBuffer::map(&ring, 3, page, 0).unwrap();
+        assert_eq!((first.index(), second.index()), (2, 3));
+        first.as_mut_slice()[..4].copy_from_slice(b"ring");
+        assert_eq!(write_fixed(&ring, fds[1], &first), 4);
+
+        // Emptying slot 3 leaves slot 2, and the table, in place.
+        let stale = second.index();
+        drop(second);
+        assert_eq!(write_fixed(&ring, fds[1], &first), 4);
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.write_fixed(fds[1], first.as_slice(), 4, 0, stale);
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.get_result(), -libc::EFAULT);
+        ring.seen(cqe);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn incremental_recv_resolves_the_consumed_slice() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let bufs = match ring.register_buf_ring_incremental(1, 64, 6) {
+            Ok(bufs) => bufs,
+            // Kernels before 6.12.
+            Err(_) => return,
+        };
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) },
+            0
+        );
+        // Two small messages share the one buffer, each picking up where the
+        // last left off.
+        for (msg, offset) in [(&b"hello"[..], 0), (b"world!", 5)] {
+            let n = unsafe { libc::send(fds[1], msg.as_ptr().cast(), msg.len(), 0) };
+            assert_eq!(n, msg.len() as isize);
+            ring.get_sqe()
+                .unwrap()
+                .recv_buf_select(fds[0], bufs.bgid(), bufs.buf_size(), 0);
+            ring.submit().unwrap();
+            let cqe = ring.wait().unwrap();
+            assert_eq!(cqe.get_result(), msg.len() as i32);
+            assert!(cqe.buffer_more());
+            assert_eq!(bufs.resolve(&cqe), Some((0, offset, msg.len())));
+            ring.seen(cqe);
+            assert_eq!(&bufs.buffer(0)[offset..offset + msg.len()], msg);
+        }
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn read_fixed_into_a_huge_page() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        ring.register_buffers_sparse(1).unwrap();
+        let mut huge = match ring.register_huge_buffer(0, 2 << 20) {
+            Ok(huge) => huge,
+            // No huge pages reserved, or none of that size.
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOMEM | libc::EINVAL)) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(
+            unsafe { libc::write(fds[1], b"huge".as_ptr().cast(), 4) },
+            4
+        );
+
+        let index = huge.index();
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read_fixed(fds[0], huge.as_mut_slice(), 4, 0, index);
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.get_result(), 4);
+        ring.seen(cqe);
+        assert_eq!(&huge.as_slice()[..4], b"huge");
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -1,61 +1,699 @@
-use std::io;
+use std::{
+    io,
+    os::unix::prelude::{FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+    task::Waker,
+};
 use uring_sys::*;
 
-use super::{Operation, UserData};
+use super::{
+    probe,
+    sys::{
+        IORING_CQE_BUFFER_SHIFT, IORING_CQE_F_BUFFER, IORING_CQE_F_BUF_MORE, IORING_CQE_F_MORE,
+        IORING_CQE_F_NOTIF, IORING_CQE_F_SOCK_NONEMPTY,
+    },
+    tag::{self, TagKind},
+    Identifier, Operation, Rring, RringError, UserData,
+};
 
 pub struct Cqe {
     pub(crate) _inner: *mut io_uring_cqe,
 }
 #[derive(Debug)]
 pub struct OperationError {
-    op: Operation,
+    // `None` when the failed operation carried no user data.
+    op: Option<Operation>,
+    // Copied from the user data, which has been dropped by the time the
+    // error reaches the caller.
+    id: Option<Identifier>,
+    srcfd: Option<RawFd>,
+    waker: Option<Waker>,
     err: io::Error,
+    // The kernel rejected the operation with EINVAL because it doesn't know
+    // the opcode, see `is_unsupported`.
+    unsupported: bool,
+}
+
+// Everything a handler needs from a completion, copied out of the CQ so it
+// outlives the entry.
+pub struct Completion<T> {
+    pub result: io::Result<i32>,
+    pub flags: u32,
+    // Set when the kernel picked a provided buffer for the operation.
+    pub buffer_id: Option<u16>,
+    pub user_data: Option<Box<UserData<T>>>,
+}
+
+// The outcome of a completion in terms of what the operation does, see
+// `Cqe::into_result`.
+#[derive(Debug)]
+pub enum CqeResult {
+    // Reads, writes, sends, receives and splices.
+    BytesTransferred(usize),
+    // An open or accept that returned a new fd, now owned by the result.
+    NewFd(OwnedFd),
+    // Everything else that succeeded, including a timeout that expired.
+    Success,
+    Cancelled,
+    Failed(io::Error),
+}
+
+// Yields the completions that were ready when `Rring::drain_completions` was
+// called. Entries are released when the iterator is dropped; any it didn't
+// get to stay in the queue.
+pub struct DrainCompletions<'ring, T> {
+    ring: &'ring Rring,
+    head: u32,
+    ready: u32,
+    taken: u32,
+    _data: std::marker::PhantomData<T>,
+}
+
+impl<'ring, T> DrainCompletions<'ring, T> {
+    pub(crate) fn new(ring: &'ring Rring) -> Self {
+        unsafe {
+            Self {
+                ring,
+                head: *(*ring._inner).cq.khead,
+                ready: io_uring_cq_ready(ring._inner),
+                taken: 0,
+                _data: std::marker::PhantomData,
+            }
+        }
+    }
+}
+
+impl<T> Iterator for DrainCompletions<'_, T> {
+    type Item = Completion<T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.taken == self.ready {
+            return None;
+        }
+        let cqe = unsafe {
+            let cq = &(*self.ring._inner).cq;
+            let index = self.head.wrapping_add(self.taken) & *cq.kring_mask;
+            Cqe::from_raw(cq.cqes.add(index as usize))
+        };
+        self.taken += 1;
+        self.ring.note_seen(&cqe);
+        Some(cqe.into_completion())
+    }
+}
+
+impl<T> Drop for DrainCompletions<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            io_uring_cq_advance(self.ring._inner, self.taken);
+        }
+    }
 }
 
 impl OperationError {
-    fn op_err(op: Operation, err_code: i32) -> Self {
+    fn op_err(op: Option<Operation>, err_code: i32) -> Self {
         Self {
             op,
+            id: None,
+            srcfd: None,
+            waker: None,
             err: io::Error::from_raw_os_error(err_code),
+            unsupported: unsupported(op, err_code, probe::kernel_lacks),
         }
     }
+    fn from_data<T>(data: &UserData<T>, err_code: i32) -> Self {
+        Self {
+            id: Some(data.id()),
+            srcfd: Some(data.srcfd()),
+            waker: data.waker().cloned(),
+            ..Self::op_err(Some(data.op()), err_code)
+        }
+    }
+    pub fn op(&self) -> Option<Operation> {
+        self.op
+    }
+    pub fn id(&self) -> Option<Identifier> {
+        self.id
+    }
+    pub fn srcfd(&self) -> Option<RawFd> {
+        self.srcfd
+    }
+    // See `UserData::with_waker`.
+    pub fn wake(&mut self) -> bool {
+        self.waker.take().map(Waker::wake).is_some()
+    }
+    pub fn io_error(&self) -> &io::Error {
+        &self.err
+    }
+    // The operation failed with EINVAL and the kernel's opcode probe says it
+    // doesn't support the operation at all, as opposed to rejecting this
+    // particular set of arguments.
+    pub fn is_unsupported(&self) -> bool {
+        self.unsupported
+    }
+    // The operation was cancelled rather than failing on its own, e.g. by
+    // `Sqe::cancel_all` during shutdown or an expired link timeout.
+    pub fn is_cancelled(&self) -> bool {
+        self.err.raw_os_error() == Some(libc::ECANCELED)
+    }
 }
 
 impl std::fmt::Display for OperationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Error when performing IO operation {:?}: {}",
-            self.op,
-            self.err.to_string()
-        )
+        match self.op {
+            Some(op) if self.unsupported => write!(
+                f,
+                "Error when performing IO operation {:?}: {}",
+                op,
+                RringError::Unsupported(op)
+            ),
+            Some(op) => write!(
+                f,
+                "Error when performing IO operation {:?}: {}",
+                op, self.err
+            ),
+            None => write!(f, "Error when performing IO operation: {}", self.err),
+        }
     }
 }
 
 impl std::error::Error for OperationError {}
 
+impl From<OperationError> for RringError {
+    fn from(err: OperationError) -> Self {
+        match err.op {
+            Some(op) if err.unsupported => Self::Unsupported(op),
+            _ => Self::Io(err.err),
+        }
+    }
+}
+
+// Only EINVAL can mean an unknown opcode, and only then is the probe
+// consulted.
+fn unsupported(
+    op: Option<Operation>,
+    err_code: i32,
+    lacks: impl FnOnce(Operation) -> bool,
+) -> bool {
+    err_code == libc::EINVAL && op.is_some_and(lacks)
+}
+
 impl Cqe {
     pub(crate) fn from_raw(raw: *mut io_uring_cqe) -> Self {
         Self { _inner: raw }
     }
+    // A failure is reported even if the operation had no user data, e.g. a
+    // fire-and-forget op submitted with `IOSQE_CQE_SKIP_SUCCESS`. The user
+    // data of a failed operation is dropped, keeping its op, id and fd in
+    // the error.
     pub fn get_data<T>(&self) -> Result<Option<Box<UserData<T>>>, OperationError> {
         let op_result = self.get_result();
         let ptr = unsafe { io_uring_cqe_get_data(self._inner) };
         if ptr.is_null() {
-            Ok(None)
+            if op_result < 0 {
+                Err(OperationError::op_err(None, -op_result))
+            } else {
+                Ok(None)
+            }
+        } else if tag::is_tag(ptr as u64) {
+            match self.tagged_op() {
+                Some(op) if op_result < 0 => Err(OperationError::op_err(Some(op), -op_result)),
+                _ => Ok(None),
+            }
+        } else if self.has_more() {
+            // Later completions still refer to the user data, so it can't
+            // be reclaimed yet; see `peek_data`.
+            if op_result < 0 {
+                let data = unsafe { &*ptr.cast::<UserData<T>>() };
+                Err(OperationError::from_data(data, -op_result))
+            } else {
+                Ok(None)
+            }
         } else {
-            let data_ptr: *mut UserData<T> = ptr.cast();
+            let boxed_data = unsafe { Box::from_raw(ptr.cast::<UserData<T>>()) };
             if op_result < 0 {
-                let op = unsafe { (*data_ptr).op() };
-                let err = OperationError::op_err(op, -op_result);
-                Err(err)
+                Err(OperationError::from_data(&boxed_data, -op_result))
             } else {
-                let boxed_data = unsafe { Box::from_raw(data_ptr) };
                 Ok(Some(boxed_data))
             }
         }
     }
+    // Reclaims the boxed user data whatever the outcome, dropping it when the
+    // operation failed. `None` if no `UserData` was attached, or while more
+    // completions for the operation are to come; an operation tagged under
+    // `Rring::tag_operations` still reports its failure.
+    pub(crate) fn take_data<T>(&self) -> Option<Result<Box<UserData<T>>, OperationError>> {
+        let ptr = unsafe { io_uring_cqe_get_data(self._inner) };
+        if let Some(op) = self.tagged_op() {
+            let op_result = self.get_result();
+            return (op_result < 0).then(|| Err(OperationError::op_err(Some(op), -op_result)));
+        }
+        if ptr.is_null() || tag::is_tag(ptr as u64) || self.has_more() {
+            return None;
+        }
+        let boxed = unsafe { Box::from_raw(ptr.cast::<UserData<T>>()) };
+        let op_result = self.get_result();
+        if op_result < 0 {
+            Some(Err(OperationError::from_data(&boxed, -op_result)))
+        } else {
+            Some(Ok(boxed))
+        }
+    }
+    // Borrows the user data without reclaiming it, so the same box can be
+    // handed to a new SQE with `Sqe::reuse_user_data`.
+    pub fn peek_data<T>(&self) -> Option<&UserData<T>> {
+        let ptr = unsafe { io_uring_cqe_get_data(self._inner) };
+        if ptr.is_null() || tag::is_tag(ptr as u64) {
+            None
+        } else {
+            Some(unsafe { &*ptr.cast::<UserData<T>>() })
+        }
+    }
+    // Takes back the reference stored by `Sqe::set_shared_data`. Only this
+    // operation's reference is released, the state itself stays alive for as
+    // long as anyone else holds it.
+    pub fn get_shared_data<T>(&self) -> Option<Arc<T>> {
+        let ptr = unsafe { io_uring_cqe_get_data(self._inner) };
+        if ptr.is_null() || tag::is_tag(ptr as u64) {
+            None
+        } else {
+            Some(unsafe { Arc::from_raw(ptr as *const T) })
+        }
+    }
     pub fn get_result(&self) -> i32 {
         unsafe { (*self._inner).res }
     }
+    // How many of the `requested` buffers a `Sqe::provide_buffers` added.
+    // Older kernels report the count, newer ones report 0 as soon as any
+    // were added, so there a provision cut short by the group's limit of
+    // 65535 buffers only shows up as EOVERFLOW on the next one.
+    pub fn provided_buffers(&self, requested: u32) -> crate::Result<u32> {
+        match self.get_result() {
+            res if res < 0 => Err(RringError::from_errno(-res)),
+            0 => Ok(requested),
+            res => Ok(res as u32),
+        }
+    }
+    // How many slots a `Sqe::files_update` replaced. That is a count of
+    // files, not bytes, and may fall short of the request if an fd in the
+    // middle was bad.
+    pub fn files_updated(&self) -> crate::Result<u32> {
+        match self.get_result() {
+            res if res < 0 => Err(RringError::from_errno(-res)),
+            res => Ok(res as u32),
+        }
+    }
+    // See `OperationError::is_cancelled`.
+    pub fn was_cancelled(&self) -> bool {
+        self.get_result() == -libc::ECANCELED
+    }
+    // Unlike `connect(2)` on a non-blocking socket, a connect through the ring
+    // never yields EINPROGRESS: the completion only arrives once the
+    // connection is established or has failed.
+    pub fn connect_outcome(&self) -> crate::Result<()> {
+        match self.get_result() {
+            res if res < 0 => Err(RringError::from_errno(-res)),
+            _ => Ok(()),
+        }
+    }
+    // Set on a recv completion when more data was already queued on the
+    // socket, so the next recv can be issued right away without polling.
+    pub fn socket_nonempty(&self) -> bool {
+        self.flags() & IORING_CQE_F_SOCK_NONEMPTY != 0
+    }
+    pub fn is_msg_ring(&self) -> bool {
+        self.msg_ring_payload().is_some()
+    }
+    // The `data` and `len` passed to `Sqe::msg_ring` by the sending ring.
+    pub fn msg_ring_payload(&self) -> Option<(u64, u32)> {
+        match tag::decode(self.user_data()) {
+            Some((TagKind::MsgRing, data)) => Some((data, self.get_result() as u32)),
+            _ => None,
+        }
+    }
+    // Reclaims the user data and copies out the rest of the entry. The CQ slot
+    // itself is still occupied afterwards; `Rring::wait_completion` converts
+    // and releases in one go.
+    pub fn into_completion<T>(self) -> Completion<T> {
+        let res = self.get_result();
+        let flags = self.flags();
+        let ptr = unsafe { io_uring_cqe_get_data(self._inner) };
+        let user_data = if ptr.is_null() || tag::is_tag(ptr as u64) || self.has_more() {
+            None
+        } else {
+            Some(unsafe { Box::from_raw(ptr.cast::<UserData<T>>()) })
+        };
+        Completion {
+            result: if res < 0 {
+                Err(io::Error::from_raw_os_error(-res))
+            } else {
+                Ok(res)
+            },
+            flags,
+            buffer_id: self.buffer_id(),
+            user_data,
+        }
+    }
+    // Interprets the result by the operation recorded under
+    // `Rring::tag_operations`; without that the kind of result can't be told
+    // and a successful one comes back as `BytesTransferred`. Meant for
+    // operations without boxed `UserData`, which would be leaked here. Like
+    // `into_completion` this leaves the CQ slot occupied; see
+    // `Rring::wait_result`.
+    pub fn into_result(self) -> CqeResult {
+        let res = self.get_result();
+        let op = self.tagged_op();
+        match -res {
+            libc::ECANCELED => return CqeResult::Cancelled,
+            libc::ETIME if matches!(op, Some(Operation::Timeout)) => return CqeResult::Success,
+            errno if errno > 0 => return CqeResult::Failed(io::Error::from_raw_os_error(errno)),
+            _ => {}
+        }
+        match op {
+            Some(Operation::Openat | Operation::Openat2 | Operation::Accept)
+                if !self.tagged_direct() =>
+            {
+                CqeResult::NewFd(unsafe { OwnedFd::from_raw_fd(res) })
+            }
+            Some(
+                Operation::Openat
+                | Operation::Openat2
+                | Operation::Accept
+                | Operation::Fsync
+                | Operation::Close
+                | Operation::Timeout
+                | Operation::Nop
+                | Operation::Fallocate
+                | Operation::Renameat
+                | Operation::Unlinkat
+                | Operation::Mkdirat,
+            ) => CqeResult::Success,
+            _ => CqeResult::BytesTransferred(res as usize),
+        }
+    }
+    // The tag of a fixed file the kernel has finished with, as passed to
+    // `Rring::register_files_tagged`. These entries don't belong to any
+    // submission.
+    pub fn rsrc_tag(&self) -> Option<u64> {
+        match tag::decode(self.user_data()) {
+            Some((TagKind::RsrcTag, tag)) => Some(tag),
+            _ => None,
+        }
+    }
+    // Extends `buf` by the bytes a `Sqe::recv_bytes` received into it and
+    // returns how many that was.
+    //
+    // Safety: `buf` must be the buffer passed to `recv_bytes` for this
+    // completion, untouched since.
+    #[cfg(feature = "bytes")]
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn commit_bytes(&self, buf: &mut bytes::BytesMut) -> crate::Result<usize> {
+        let res = self.get_result();
+        if res < 0 {
+            return Err(RringError::from_errno(-res));
+        }
+        buf.set_len(buf.len() + res as usize);
+        Ok(res as usize)
+    }
+    // Posted for `RringWaker::wake` rather than for an operation.
+    pub fn is_wake(&self) -> bool {
+        matches!(tag::decode(self.user_data()), Some((TagKind::Waker, _)))
+    }
+    // The raw CQE flags, see `CqeFlag`.
+    pub fn flags(&self) -> u32 {
+        unsafe { (*self._inner).flags }
+    }
+    // The provided buffer the kernel picked for the operation, if any.
+    pub fn buffer_id(&self) -> Option<u16> {
+        let flags = self.flags();
+        (flags & IORING_CQE_F_BUFFER != 0).then_some((flags >> IORING_CQE_BUFFER_SHIFT) as u16)
+    }
+    // In incremental mode (see `BufRing::resolve`), the kernel only used up
+    // part of the buffer and will keep filling the rest, so it mustn't be
+    // recycled yet.
+    pub fn buffer_more(&self) -> bool {
+        self.flags() & IORING_CQE_F_BUF_MORE != 0
+    }
+    // The operation stays armed and will post further completions.
+    pub fn has_more(&self) -> bool {
+        self.flags() & IORING_CQE_F_MORE != 0
+    }
+    // The buffer of a `send_zc` may be reused.
+    pub fn is_notif(&self) -> bool {
+        self.flags() & IORING_CQE_F_NOTIF != 0
+    }
+    // The operation recorded under `Rring::tag_operations`.
+    fn tagged_op(&self) -> Option<Operation> {
+        match tag::decode(self.user_data()) {
+            Some((TagKind::Op, raw)) => Operation::from_raw(raw & !tag::OP_DIRECT),
+            _ => None,
+        }
+    }
+    // The tagged operation installed a fixed file, see `tag::OP_DIRECT`.
+    fn tagged_direct(&self) -> bool {
+        matches!(tag::decode(self.user_data()), Some((TagKind::Op, raw)) if raw & tag::OP_DIRECT != 0)
+    }
+    pub(crate) fn user_data(&self) -> u64 {
+        unsafe { (*self._inner).user_data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, os::unix::prelude::AsRawFd};
+
+    use super::{unsupported, OperationError};
+    use crate::{
+        accept::raw_socket_addr, Identifier, Operation, Rring, RringError, SetupFlag, UserData,
+    };
+
+    #[test]
+    fn peeked_data_is_reused_to_finish_a_short_read() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { libc::write(fds[1], b"ri".as_ptr().cast(), 2) }, 2);
+
+        let mut buf = [0u8; 4];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut buf, 4, -1);
+        let user_data = UserData::with_data(Operation::Read, Identifier(5), fds[0], 4usize);
+        let token = sqe.set_user_data(user_data).unwrap();
+        ring.submit().unwrap();
+
+        let cqe = ring.wait().unwrap();
+        let data = cqe.peek_data::<usize>().unwrap();
+        let (got, wanted) = (cqe.get_result() as usize, *data.data().unwrap());
+        assert_eq!((got, wanted), (2, 4));
+        assert_eq!(data.id().0, 5);
+        // Short read: ask for the rest with the same box.
+        assert_eq!(unsafe { libc::write(fds[1], b"ng".as_ptr().cast(), 2) }, 2);
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut buf[got..], (wanted - got) as u32, -1);
+        sqe.reuse_user_data(&cqe).unwrap();
+        ring.seen(cqe);
+        ring.submit().unwrap();
+
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.get_result(), 2);
+        assert_eq!(cqe.user_data(), token);
+        let data = cqe.get_data::<usize>().unwrap().unwrap();
+        assert_eq!(data.id().0, 5);
+        ring.seen(cqe);
+        assert_eq!(&buf, b"ring");
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn unsupported_needs_einval_from_an_opcode_the_kernel_lacks() {
+        let lacks_openat2 = |op| matches!(op, Operation::Openat2);
+        assert!(unsupported(
+            Some(Operation::Openat2),
+            libc::EINVAL,
+            lacks_openat2
+        ));
+        assert!(!unsupported(
+            Some(Operation::Openat2),
+            libc::EBADF,
+            lacks_openat2
+        ));
+        assert!(!unsupported(
+            Some(Operation::Read),
+            libc::EINVAL,
+            lacks_openat2
+        ));
+        assert!(!unsupported(None, libc::EINVAL, lacks_openat2));
+    }
+
+    #[test]
+    fn cancelled_read_was_cancelled() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut buf = [0u8; 1];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut buf, 1, 0);
+        let token = sqe
+            .set_user_data(UserData::<()>::new(Operation::Read, Identifier(0), fds[0]))
+            .unwrap();
+        ring.submit().unwrap();
+        ring.get_sqe().unwrap().cancel(token, 0);
+        ring.submit().unwrap();
+
+        let (mut read, mut cancel) = (None, None);
+        for _ in 0..2 {
+            let cqe = ring.wait().unwrap();
+            let slot = if cqe.user_data() == token {
+                &mut read
+            } else {
+                &mut cancel
+            };
+            *slot = Some(cqe.was_cancelled());
+            let data = cqe.get_data::<()>();
+            if let Err(err) = &data {
+                assert!(err.is_cancelled());
+            }
+            ring.seen(cqe);
+        }
+        assert_eq!(read, Some(true));
+        assert_eq!(cancel, Some(false));
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn files_update_reports_the_slots_it_replaced() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        ring.register_files_sparse(4).unwrap();
+        let mut pipes = [[0; 2]; 2];
+        for pipe in pipes.iter_mut() {
+            assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        }
+        let mut fds = [pipes[0][0], pipes[0][1], pipes[1][0]];
+        ring.get_sqe().unwrap().files_update(&mut fds, 1);
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.files_updated().unwrap(), 3);
+        ring.seen(cqe);
+
+        for fd in pipes.iter().flatten() {
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    #[test]
+    fn every_rring_error_variant_can_be_produced() {
+        let ring = Rring::new(2, SetupFlag::empty()).unwrap();
+
+        let mut byte = [0u8; 1];
+        let io = ring
+            .run_one(|sqe| sqe.read(-1, &mut byte, 1, 0))
+            .unwrap_err();
+
+        let mut bufs = [0u8; 4];
+        let overflow = ring.provide_buffers_all(&mut bufs, 4, 2, 0, 0).unwrap_err();
+
+        // Every operation the crate knows is supported here, so the failure
+        // is made up as the completion path would record it.
+        let unsupported = RringError::from(OperationError {
+            op: Some(Operation::Openat2),
+            id: None,
+            srcfd: None,
+            waker: None,
+            err: io::Error::from_raw_os_error(libc::EINVAL),
+            unsupported: true,
+        });
+
+        ring.get_sqe().unwrap().nop();
+        ring.get_sqe().unwrap().nop();
+        let full = ring.get_sqe().err().unwrap();
+        ring.submit_and_wait(2).unwrap();
+
+        // No wildcard arm, so a new variant has to be added here too.
+        let kinds: Vec<_> = [io, full, unsupported, overflow]
+            .iter()
+            .map(|err| match err {
+                RringError::Io(err) => {
+                    assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+                    "Io"
+                }
+                RringError::QueueFull => "QueueFull",
+                RringError::Unsupported(op) => {
+                    assert!(matches!(op, Operation::Openat2));
+                    "Unsupported"
+                }
+                RringError::WouldOverflow => "WouldOverflow",
+            })
+            .collect();
+        assert_eq!(kinds, ["Io", "QueueFull", "Unsupported", "WouldOverflow"]);
+    }
+
+    #[test]
+    fn msg_ring_between_two_rings() {
+        let sender = Rring::new(8, SetupFlag::empty()).unwrap();
+        let target = Rring::new(8, SetupFlag::empty()).unwrap();
+        sender
+            .get_sqe()
+            .unwrap()
+            .msg_ring(target.as_raw_fd(), 42, 0x1234);
+        sender.submit().unwrap();
+        // A regular completion on the target, for contrast.
+        target.get_sqe().unwrap().nop();
+        target.submit().unwrap();
+
+        let cqe = sender.wait().unwrap();
+        assert_eq!(cqe.get_result(), 0);
+        assert!(!cqe.is_msg_ring());
+        sender.seen(cqe);
+
+        let (mut messages, mut others) = (Vec::new(), 0);
+        for _ in 0..2 {
+            let cqe = target.wait().unwrap();
+            match cqe.msg_ring_payload() {
+                Some(payload) => {
+                    assert!(cqe.is_msg_ring());
+                    messages.push(payload);
+                }
+                None => others += 1,
+            }
+            target.seen(cqe);
+        }
+        assert_eq!(messages, [(0x1234, 42)]);
+        assert_eq!(others, 1);
+    }
+
+    fn connect_to(ring: &Rring, addr: std::net::SocketAddr) -> crate::Result<()> {
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(socket >= 0);
+        let (mut storage, len) = raw_socket_addr(&addr);
+        ring.get_sqe().unwrap().connect(
+            socket,
+            (&mut storage as *mut libc::sockaddr_storage).cast(),
+            len,
+        );
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        let outcome = cqe.connect_outcome();
+        ring.seen(cqe);
+        unsafe { libc::close(socket) };
+        outcome
+    }
+
+    #[test]
+    fn connect_outcome_against_a_listener_and_a_refused_port() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        connect_to(&ring, listener.local_addr().unwrap()).unwrap();
+
+        // Nothing listens on the port once the listener is gone, and the
+        // refusal comes back as such rather than as EINPROGRESS.
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let err = connect_to(&ring, addr).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ECONNREFUSED));
+    }
 }
@@ -0,0 +1,76 @@
+use std::{fmt, io};
+
+use crate::Operation;
+
+// The error type returned throughout the crate. Conditions the crate detects
+// itself get their own variants, so callers can match on them instead of
+// inspecting an `io::Error`.
+#[derive(Debug)]
+pub enum RringError {
+    Io(io::Error),
+    // No free SQE; submit what is queued and try again.
+    QueueFull,
+    // The running kernel doesn't support the operation.
+    Unsupported(Operation),
+    // The request doesn't fit the buffer or ring it targets.
+    WouldOverflow,
+}
+
+pub type Result<T> = std::result::Result<T, RringError>;
+
+impl RringError {
+    pub(crate) fn from_errno(errno: i32) -> Self {
+        Self::Io(io::Error::from_raw_os_error(errno))
+    }
+    pub(crate) fn invalid_input(msg: impl Into<String>) -> Self {
+        Self::Io(io::Error::new(io::ErrorKind::InvalidInput, msg.into()))
+    }
+    // The OS error code behind an `Io` error, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Self::Io(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::QueueFull => write!(f, "SQ is currently full"),
+            Self::Unsupported(op) => write!(f, "{:?} is not supported by this kernel", op),
+            Self::WouldOverflow => write!(f, "request exceeds the capacity of its target"),
+        }
+    }
+}
+
+impl std::error::Error for RringError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RringError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<RringError> for io::Error {
+    fn from(err: RringError) -> Self {
+        match err {
+            RringError::Io(err) => err,
+            RringError::QueueFull => io::Error::new(io::ErrorKind::WouldBlock, err.to_string()),
+            RringError::Unsupported(_) => {
+                io::Error::new(io::ErrorKind::Unsupported, err.to_string())
+            }
+            RringError::WouldOverflow => {
+                io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+            }
+        }
+    }
+}
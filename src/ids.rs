@@ -0,0 +1,149 @@
+// The in-flight operations of each `Identifier`, kept while
+// `Rring::track_identifiers` is on. The kernel only matches a cancellation
+// against the exact `user_data` of an operation, the address of its boxed
+// `UserData`, so `cancel_by_id` has to look those up here. Entries are added
+// when user data is attached to an SQE and dropped once the operation's last
+// completion is released. The map is locked because the halves of a split
+// ring add and drop entries from different threads.
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{tag::TagKind, Identifier, Result, Rring, RringError};
+
+#[derive(Default)]
+pub(crate) struct IdRegistry {
+    tokens: Mutex<HashMap<u64, Entry>>,
+}
+
+struct Entry {
+    id: Identifier,
+    // Submission order, so `cancel_by_id` without `all` picks the oldest.
+    seq: u64,
+    // A box handed on with `Sqe::reuse_user_data` is registered again before
+    // the completion that handed it on is released.
+    refs: u32,
+}
+
+impl IdRegistry {
+    pub(crate) fn insert(&self, token: u64, id: Identifier, seq: u64) {
+        let entry = Entry { id, seq, refs: 1 };
+        self.tokens.lock().unwrap().insert(token, entry);
+    }
+    pub(crate) fn reuse(&self, token: u64) {
+        if let Some(entry) = self.tokens.lock().unwrap().get_mut(&token) {
+            entry.refs += 1;
+        }
+    }
+    pub(crate) fn remove(&self, token: u64) {
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some(entry) = tokens.get_mut(&token) {
+            entry.refs -= 1;
+            if entry.refs == 0 {
+                tokens.remove(&token);
+            }
+        }
+    }
+    // The `user_data` of the operations tagged `id`, oldest first.
+    fn tokens(&self, id: Identifier) -> Vec<u64> {
+        let tokens = self.tokens.lock().unwrap();
+        let mut matches: Vec<_> = tokens
+            .iter()
+            .filter(|(_, entry)| entry.id == id)
+            .map(|(&token, entry)| (entry.seq, token))
+            .collect();
+        matches.sort_unstable();
+        matches.into_iter().map(|(_, token)| token).collect()
+    }
+}
+
+impl Rring {
+    // When enabled, SQEs prepared afterwards record the `Identifier` of the
+    // user data attached to them, so they can be cancelled with
+    // `cancel_by_id`. Costs a map insert per operation and a removal per
+    // completion.
+    pub fn track_identifiers(&mut self, enabled: bool) {
+        self.ids = enabled.then(Default::default);
+    }
+    // Prepares a cancel for the oldest in-flight operation tagged `id`, or
+    // for each of them with `all`, and returns how many were targeted; they
+    // go out with the next submit and complete with ECANCELED. The cancels'
+    // own completions carry no user data. Fails if `track_identifiers` is
+    // off, or with `QueueFull` if the SQ runs out, leaving the cancels
+    // prepared so far in place.
+    pub fn cancel_by_id(&self, id: Identifier, all: bool) -> Result<u32> {
+        let ids = self.ids.as_ref().ok_or_else(|| {
+            RringError::invalid_input("cancel_by_id needs Rring::track_identifiers")
+        })?;
+        let mut tokens = ids.tokens(id);
+        if !all {
+            tokens.truncate(1);
+        }
+        for &token in &tokens {
+            let mut sqe = self.get_sqe()?;
+            sqe.cancel(token, 0);
+            sqe.set_tag(TagKind::Cancel, 0);
+        }
+        Ok(tokens.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Identifier, Operation, Rring, SetupFlag, UserData};
+
+    #[test]
+    fn cancel_by_id_cancels_every_operation_with_the_id() {
+        let mut ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        ring.track_identifiers(true);
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Two reads on an empty pipe share an id, a third has its own.
+        let mut bufs = [[0u8; 1]; 3];
+        for (buf, id) in bufs.iter_mut().zip([7, 7, 8]) {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.read(fds[0], buf, 1, 0);
+            sqe.set_user_data(UserData::<()>::new(Operation::Read, Identifier(id), fds[0]))
+                .unwrap();
+        }
+        ring.submit().unwrap();
+
+        assert_eq!(ring.cancel_by_id(Identifier(7), true).unwrap(), 2);
+        ring.submit().unwrap();
+        let mut cancelled = 0;
+        for _ in 0..4 {
+            let cqe = ring.wait().unwrap();
+            match cqe.get_data::<()>() {
+                Err(err) => {
+                    assert!(err.is_cancelled());
+                    assert_eq!(err.id().unwrap(), Identifier(7));
+                    cancelled += 1;
+                }
+                // The cancels themselves.
+                Ok(data) => {
+                    assert!(data.is_none());
+                    assert_eq!(cqe.get_result(), 0);
+                }
+            }
+            ring.seen(cqe);
+        }
+        assert_eq!(cancelled, 2);
+        assert!(ring.peek().is_none());
+        // Their entries went with the completions, only id 8 is left.
+        assert_eq!(ring.cancel_by_id(Identifier(7), true).unwrap(), 0);
+        assert_eq!(ring.cancel_by_id(Identifier(8), false).unwrap(), 1);
+        ring.submit().unwrap();
+        for _ in 0..2 {
+            let cqe = ring.wait().unwrap();
+            ring.seen(cqe);
+        }
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn cancel_by_id_needs_tracking() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        assert!(ring.cancel_by_id(Identifier(0), true).is_err());
+    }
+}
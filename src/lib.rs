@@ -1,25 +1,75 @@
+mod accept;
+pub mod batch;
+mod blocking;
+pub mod buffer;
 pub mod cqe;
+mod error;
+mod ids;
+mod metadata;
+mod multishot;
+mod probe;
+mod recv_buf;
+mod register;
+#[cfg(feature = "mio")]
+mod source;
+mod split;
 pub mod sqe;
+mod stats;
+pub mod sys;
+mod tag;
+mod waker;
 
 use std::{
     alloc::{alloc_zeroed, dealloc, Layout},
+    cell::RefCell,
     io,
-    os::unix::prelude::RawFd,
+    os::unix::prelude::{AsRawFd, RawFd},
     ptr::addr_of_mut,
+    sync::{
+        atomic::{fence, AtomicU32, Ordering},
+        Arc,
+    },
+    task::Waker,
+    thread::{self, ThreadId},
+    time::Duration,
 };
 
 use uring_sys::*;
 
 use bitflags::bitflags;
 
-use jemallocator::Jemalloc;
+use self::{
+    batch::{AutoFlushRring, SubmissionBatch},
+    cqe::{Completion, Cqe, CqeResult, DrainCompletions, OperationError},
+    ids::IdRegistry,
+    sqe::Sqe,
+    stats::Counters,
+    tag::TagKind,
+    waker::RetiredWakers,
+};
 
-use anyhow::anyhow;
+pub use nix::sched::CpuSet;
 
-use self::{cqe::Cqe, sqe::Sqe};
+pub use self::{
+    accept::AcceptSlot,
+    error::{Result, RringError},
+    metadata::{FileType, Metadata},
+    multishot::MultishotHandle,
+    probe::Probe,
+    recv_buf::RecvGuard,
+    split::{Completer, Submitter},
+    stats::RingStats,
+    waker::RringWaker,
+};
 
-#[global_allocator]
-pub static GLOBAL: Jemalloc = Jemalloc;
+// The allocator is the application's choice, the crate itself only ever
+// allocates through `Box` and `Vec`. The `jemalloc` feature re-exports
+// jemalloc for applications that want it:
+//
+//     #[global_allocator]
+//     static GLOBAL: rring::Jemalloc = rring::Jemalloc;
+#[cfg(feature = "jemalloc")]
+pub use jemallocator::Jemalloc;
 
 // See `io_uring_setup(2)` for explianation.
 bitflags! {
@@ -31,6 +81,9 @@ bitflags! {
         const CLAMP = 0b0010000;
         const ATTACH_WQ = 0b0100000;
         const RING_DISABLED = 0b1000000;
+        const SUBMIT_ALL = 0b10000000;
+        const SINGLE_ISSUER = 0b1000000000000;
+        const NO_SQARRAY = 0b10000000000000000;
     }
 }
 
@@ -48,89 +101,703 @@ bitflags! {
         const EXT_ARG = 0b00100000000;
         const NATIVE_WORKERS = 0b01000000000;
         const RSRC_TAGS = 0b10000000000;
+        const MIN_TIMEOUT = 0b1000000000000000;
+    }
+}
+
+// Per-SQE flags, see `io_uring_enter(2)`.
+bitflags! {
+    pub struct SqeFlag: u8 {
+        const FIXED_FILE = 0b0000001;
+        const IO_DRAIN = 0b0000010;
+        const IO_LINK = 0b0000100;
+        const IO_HARDLINK = 0b0001000;
+        const ASYNC = 0b0010000;
+        const BUFFER_SELECT = 0b0100000;
+        const CQE_SKIP_SUCCESS = 0b1000000;
+    }
+}
+
+// Flags the kernel sets on a CQE, for `CqeFlag::from_bits_truncate` on
+// `Cqe::flags`. The upper 16 bits hold the buffer id, see `Cqe::buffer_id`.
+bitflags! {
+    pub struct CqeFlag: u32 {
+        const BUFFER = 1 << 0;
+        const MORE = 1 << 1;
+        const SOCK_NONEMPTY = 1 << 2;
+        const NOTIF = 1 << 3;
+        const BUF_MORE = 1 << 4;
     }
 }
 
+// Resources registered with the ring are handed out as handles that borrow
+// it, so the borrow checker keeps the ring alive until every handle has been
+// dropped and unregistered itself.
 pub struct Rring {
     _inner: *mut io_uring,
     _layout: Layout,
+    flags: SetupFlag,
+    features: RingFeature,
+    counters: Counters,
+    owner: ThreadId,
+    // Scratch space for `io_uring_peek_batch_cqe`, one slot per CQ entry so
+    // a single peek can always take everything that is ready.
+    cqe_buf: RefCell<Vec<*mut io_uring_cqe>>,
+    // Stands in for the SQ index array under `NO_SQARRAY`, see `with_param`.
+    _sq_array: Option<Box<[u32]>>,
+    // See `tag_operations`.
+    tag_ops: bool,
+    // See `track_identifiers`.
+    ids: Option<Arc<IdRegistry>>,
+    // See `RringWaker`.
+    retired_wakers: Arc<RetiredWakers>,
+    // The index from `register_ring_fd` and the thread it is valid on.
+    registered_fd: Option<(ThreadId, u32)>,
 }
 
 impl Rring {
-    pub fn new(entries: u32, flags: SetupFlag) -> io::Result<Self> {
+    pub fn new(entries: u32, flags: SetupFlag) -> Result<Self> {
+        Self::with_param(entries, RringParams::new(flags, RingFeature::empty()))
+    }
+    // Flags marked with `RringParams::set_optional` are dropped one at a
+    // time, newest first, for as long as the kernel refuses the setup with
+    // EINVAL, which is how it reports a flag it doesn't know. An optional
+    // SQ_POLL is also dropped on EPERM, i.e. when the caller lacks the
+    // privilege for it. `flags` tells what the ring ended up with.
+    pub fn with_param(entries: u32, mut param: RringParams) -> Result<Self> {
+        loop {
+            let errno = match Self::setup(entries, &param) {
+                Ok(ring) => return Ok(ring),
+                Err(errno) => errno,
+            };
+            let drop = match errno {
+                libc::EINVAL if !param.optional.is_empty() => {
+                    let newest = 1 << (31 - param.optional.bits().leading_zeros());
+                    SetupFlag::from_bits_truncate(newest)
+                }
+                libc::EPERM if param.optional.contains(SetupFlag::SQ_POLL) => SetupFlag::SQ_POLL,
+                _ => return Err(Self::setup_error(param.flags, errno).into()),
+            };
+            param.remove_optional(drop);
+        }
+    }
+    // A single `io_uring_setup` attempt, failing with the errno.
+    fn setup(entries: u32, param: &RringParams) -> std::result::Result<Self, i32> {
+        let mut raw = param.to_raw();
         unsafe {
             let layout: Layout = Layout::new::<io_uring>();
             let inner: *mut io_uring = alloc_zeroed(layout).cast();
-            let ret = io_uring_queue_init(entries, inner, flags.bits());
+            let ret = io_uring_queue_init_params(entries, inner, &mut raw);
             if ret < 0 {
-                let errno = -ret;
-                Err(io::Error::from_raw_os_error(errno))
+                dealloc(inner.cast(), layout);
+                Err(-ret)
             } else {
+                let cq_entries = *(*inner).cq.kring_entries as usize;
+                // The bundled liburing predates `NO_SQARRAY` (6.6+) and still
+                // fills in the index array on submit, which would now land on
+                // the start of the shared ring. The kernel no longer reads
+                // it, so point liburing at an array of our own instead.
+                let sq_array = param.flags.contains(SetupFlag::NO_SQARRAY).then(|| {
+                    let mut array =
+                        vec![0u32; *(*inner).sq.kring_entries as usize].into_boxed_slice();
+                    (*inner).sq.array = array.as_mut_ptr();
+                    array
+                });
                 Ok(Self {
                     _inner: inner,
                     _layout: layout,
+                    flags: param.flags,
+                    features: RingFeature::from_bits_truncate(raw.features),
+                    counters: Counters::default(),
+                    owner: thread::current().id(),
+                    cqe_buf: RefCell::new(vec![std::ptr::null_mut(); cq_entries]),
+                    _sq_array: sq_array,
+                    tag_ops: false,
+                    ids: None,
+                    retired_wakers: Arc::default(),
+                    registered_fd: None,
                 })
             }
         }
     }
-    pub fn with_param(entries: u32, param: RringParams) -> io::Result<Self> {
-        unsafe {
-            let layout: Layout = Layout::new::<io_uring>();
-            let inner: *mut io_uring = alloc_zeroed(layout).cast();
-            let mut param = param.to_raw();
-            let ret = io_uring_queue_init_params(entries, inner, &mut param);
-            if ret < 0 {
-                let errno = -ret;
-                Err(io::Error::from_raw_os_error(errno))
-            } else {
-                Ok(Self {
-                    _inner: inner,
-                    _layout: layout,
-                })
+    // Whether this process can set up a ring at all. Kernels built without
+    // io_uring refuse with ENOSYS, and the `kernel.io_uring_disabled` sysctl
+    // (6.6+) can refuse with EPERM, so a library can check this at startup
+    // and fall back to plain syscalls. Note that it finds out by creating a
+    // real ring and dropping it again on every call, so call it once and keep
+    // the answer rather than checking before each use.
+    pub fn is_available() -> bool {
+        Self::new(1, SetupFlag::empty()).is_ok()
+    }
+    // The `kernel.io_uring_disabled` sysctl: 1 limits io_uring to the
+    // `kernel.io_uring_group` group, 2 disables it for everyone. Kernels
+    // before 6.6 don't have it.
+    fn disabled_by_sysctl() -> bool {
+        std::fs::read_to_string("/proc/sys/kernel/io_uring_disabled")
+            .is_ok_and(|val| val.trim() != "0")
+    }
+    // The kernel reports the cases below as a bare errno. Before
+    // `SQ_POLL_NON_FIXED` (5.11) an SQ polling thread needs CAP_SYS_ADMIN.
+    fn setup_error(flags: SetupFlag, errno: i32) -> io::Error {
+        if errno == libc::ENOSYS {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring is not supported by this kernel",
+            )
+        } else if errno == libc::EPERM && Self::disabled_by_sysctl() {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "io_uring is disabled by the kernel.io_uring_disabled sysctl",
+            )
+        } else if errno == libc::EPERM && flags.contains(SetupFlag::SQ_POLL) {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SQ_POLL requires CAP_SYS_ADMIN on kernels without SQ_POLL_NON_FIXED",
+            )
+        } else {
+            io::Error::from_raw_os_error(errno)
+        }
+    }
+    // Features reported by the kernel when the ring was set up.
+    pub fn features(&self) -> RingFeature {
+        self.features
+    }
+    // The flags the ring was set up with, without any optional ones the
+    // kernel refused (see `with_param`).
+    pub fn flags(&self) -> SetupFlag {
+        self.flags
+    }
+    // With SINGLE_ISSUER the kernel rejects submissions from any task other
+    // than the one that created the ring with an unhelpful EEXIST.
+    fn debug_assert_issuer(&self) {
+        if cfg!(debug_assertions) && self.flags.contains(SetupFlag::SINGLE_ISSUER) {
+            assert_eq!(
+                thread::current().id(),
+                self.owner,
+                "SINGLE_ISSUER ring used from a thread other than the one that created it"
+            );
+        }
+    }
+    // Returns the number of SQEs consumed by the kernel. An SQE the kernel
+    // rejects while preparing it still posts a failed completion, but
+    // without SUBMIT_ALL the submission stops there, leaving the SQEs after
+    // it in the SQ for the next call (see `submit_robust`).
+    pub fn submit(&self) -> Result<u32> {
+        self.debug_assert_issuer();
+        self.cancel_retired_wakers();
+        let ret = match self.registered_index() {
+            Some(index) => unsafe { self.submit_registered(index, 0) },
+            None => unsafe { io_uring_submit(self._inner) },
+        };
+        if ret < 0 {
+            return Err(RringError::from_errno(-ret));
+        }
+        self.counters.add_submitted(ret as u64);
+        Ok(ret as u32)
+    }
+    // `submit`, then blocks until at least `wait_nr` completions are ready,
+    // in a single syscall. Returns the number submitted; reap the
+    // completions as after `wait`.
+    pub fn submit_and_wait(&self, wait_nr: u32) -> Result<u32> {
+        self.debug_assert_issuer();
+        self.cancel_retired_wakers();
+        let ret = match self.registered_index() {
+            Some(index) => unsafe { self.submit_registered(index, wait_nr) },
+            None => unsafe { io_uring_submit_and_wait(self._inner, wait_nr) },
+        };
+        if ret < 0 {
+            return Err(RringError::from_errno(-ret));
+        }
+        self.counters.add_submitted(ret as u64);
+        Ok(ret as u32)
+    }
+    fn registered_index(&self) -> Option<u32> {
+        match self.registered_fd {
+            Some((thread, index)) if thread == thread::current().id() => Some(index),
+            _ => None,
+        }
+    }
+    // `io_uring_submit_and_wait` through the index from `register_ring_fd`,
+    // which the bundled liburing predates. As there, the prepared SQEs are
+    // published to the kernel first, and with SQ_POLL the kernel is only
+    // entered if the poll thread needs waking or we wait. Returns the
+    // submit count or a negated errno.
+    unsafe fn submit_registered(&self, index: u32, wait_nr: u32) -> i32 {
+        let sq = &mut (*self._inner).sq;
+        let mask = *sq.kring_mask;
+        let mut ktail = *sq.ktail;
+        while sq.sqe_head != sq.sqe_tail {
+            *sq.array.add((ktail & mask) as usize) = sq.sqe_head & mask;
+            ktail = ktail.wrapping_add(1);
+            sq.sqe_head = sq.sqe_head.wrapping_add(1);
+        }
+        (*(sq.ktail as *const AtomicU32)).store(ktail, Ordering::Release);
+        let submitted =
+            ktail.wrapping_sub((*(sq.khead as *const AtomicU32)).load(Ordering::Acquire));
+        let mut flags = sys::IORING_ENTER_REGISTERED_RING;
+        if self.flags.contains(SetupFlag::SQ_POLL) {
+            // Orders the tail store before reading whether the thread sleeps.
+            fence(Ordering::SeqCst);
+            if *sq.kflags & IORING_SQ_NEED_WAKEUP != 0 {
+                flags |= IORING_ENTER_SQ_WAKEUP;
+            } else if wait_nr == 0 {
+                return submitted as i32;
             }
         }
+        if wait_nr > 0 || self.flags.contains(SetupFlag::IO_POLL) {
+            flags |= IORING_ENTER_GETEVENTS;
+        }
+        let ret = libc::syscall(
+            libc::SYS_io_uring_enter,
+            index,
+            submitted,
+            wait_nr,
+            flags,
+            std::ptr::null::<libc::c_void>(),
+            0,
+        );
+        if ret < 0 {
+            -io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(libc::EIO)
+        } else {
+            ret as i32
+        }
     }
-    pub fn submit(&self) -> i32 {
-        unsafe { io_uring_submit(self._inner) }
+    // Like `submit`, but keeps going until every prepared SQE has been
+    // consumed: interrupted calls are retried, and entries left behind by a
+    // short submit are handed to the kernel again. Returns the total count.
+    pub fn submit_robust(&self) -> Result<u32> {
+        let mut total = 0;
+        loop {
+            let ret = match self.submit() {
+                Ok(ret) => ret,
+                Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(err) => return Err(err),
+            };
+            total += ret;
+            // The SQ poll thread drains the ring on its own schedule, and a
+            // zero count means the kernel won't take any more right now.
+            let pending = unsafe { io_uring_sq_ready(self._inner) };
+            if pending == 0 || ret == 0 || self.flags.contains(SetupFlag::SQ_POLL) {
+                return Ok(total);
+            }
+        }
     }
-    pub fn get_sqe(&self) -> anyhow::Result<Sqe> {
+    // Submits an fsync of `fd` flagged with `IO_DRAIN`, along with anything
+    // already prepared. The kernel holds it back until every operation
+    // submitted before it has completed, and everything submitted after it
+    // until it has, so once it completes all earlier writes are durable.
+    // Writes that are still waiting in the SQ count as earlier ones.
+    pub fn barrier_fsync<T>(
+        &self,
+        fd: RawFd,
+        fsync_flags: u32,
+        user_data: UserData<T>,
+    ) -> Result<u32> {
+        let mut sqe = self.get_sqe()?;
+        sqe.fsync(fd, fsync_flags);
+        sqe.add_flags(SqeFlag::IO_DRAIN);
+        sqe.set_user_data(user_data)?;
+        self.submit_robust()
+    }
+    pub fn get_sqe(&self) -> Result<Sqe> {
+        self.debug_assert_issuer();
         unsafe {
             let raw = io_uring_get_sqe(self._inner);
             if raw.is_null() {
-                Err(anyhow!("SQ is currently full."))
+                Err(RringError::QueueFull)
             } else {
-                Ok(Sqe::from_raw(raw))
+                Ok(Sqe::from_raw(raw, self.tag_ops, self.ids.clone()))
             }
         }
     }
-    pub fn wait(&self) -> anyhow::Result<Cqe> {
+    // `get_sqe`, but a full SQ is first submitted to make room. Still fails
+    // with `QueueFull` if the kernel took nothing, e.g. while the SQ poll
+    // thread hasn't caught up yet.
+    pub fn get_sqe_or_submit(&self) -> Result<Sqe> {
+        match self.get_sqe() {
+            Err(RringError::QueueFull) => {
+                self.submit()?;
+                self.get_sqe()
+            }
+            res => res,
+        }
+    }
+    // When enabled, SQEs prepared afterwards record their `Operation` in
+    // `user_data`, so a failure reported by `Cqe::get_data` names the
+    // operation even when it was submitted without `UserData`. Costs nothing
+    // per operation; data set with `Sqe::set_user_data` takes precedence.
+    pub fn tag_operations(&mut self, enabled: bool) {
+        self.tag_ops = enabled;
+    }
+    // Arms a waker on the ring; this submits any SQEs prepared so far.
+    pub fn waker(&self) -> Result<RringWaker> {
+        RringWaker::new(self)
+    }
+    // Copies a recorded SQE (see `Sqe::to_bytes`) into a free slot and submits
+    // it along with anything already prepared, returning the submit count.
+    //
+    // Safety: the entry's addresses, `user_data` included, are handed to the
+    // kernel as they are, so whatever they pointed at when the entry was
+    // recorded must still be valid, and a boxed `UserData` must not be
+    // reclaimed twice.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn submit_raw_sqe(&self, bytes: &[u8]) -> Result<u32> {
+        let bytes: &[u8; sqe::SQE_SIZE] = bytes.try_into().map_err(|_| {
+            RringError::invalid_input(format!(
+                "an SQE is {} bytes, got {}",
+                sqe::SQE_SIZE,
+                bytes.len()
+            ))
+        })?;
+        let mut sqe = self.get_sqe()?;
+        sqe.copy_from_bytes(bytes);
+        self.submit()
+    }
+    // Number of SQEs that can still be acquired before the SQ is full.
+    pub fn sq_space_left(&self) -> u32 {
+        unsafe { io_uring_sq_space_left(self._inner) }
+    }
+    pub fn batch(&self) -> SubmissionBatch<'_> {
+        SubmissionBatch::new(self)
+    }
+    pub fn auto_flush(&self, max_pending: u32, max_delay: Option<Duration>) -> AutoFlushRring<'_> {
+        AutoFlushRring::new(self, max_pending, max_delay)
+    }
+    pub fn wait(&self) -> Result<Cqe> {
+        self.wait_cqe()
+    }
+    // The next completion if one is ready, without entering the kernel.
+    pub fn peek(&self) -> Option<Cqe> {
+        let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
+        let ret = unsafe { io_uring_peek_cqe(self._inner, &mut cqe) };
+        (ret == 0 && !cqe.is_null()).then(|| Cqe::from_raw(cqe))
+    }
+    pub(crate) fn wait_cqe(&self) -> Result<Cqe> {
         let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
         let ptr: *mut *mut io_uring_cqe = addr_of_mut!(cqe);
         let retval = unsafe { io_uring_wait_cqe(self._inner, ptr) };
         if retval != 0 {
             let eno = -retval;
-            Err(anyhow::Error::from(io::Error::from_raw_os_error(eno)))
+            Err(RringError::from_errno(eno))
         } else {
             Ok(Cqe::from_raw(cqe))
         }
     }
+    // Waits for a completion without a lost-wakeup window: peek, and only if
+    // nothing is there enter the kernel to wait for one, then peek again.
+    // The kernel re-checks the CQ after queueing the waiter, so a completion
+    // that lands between the peek and the enter makes the enter return right
+    // away. The enter may also return with no completion for us, e.g. on a
+    // signal or when another reaper got there first, so the result is never
+    // assumed and the loop peeks again.
+    pub fn block_for_completion(&self) -> Result<Cqe> {
+        loop {
+            let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
+            if unsafe { io_uring_peek_cqe(self._inner, &mut cqe) } == 0 && !cqe.is_null() {
+                return Ok(Cqe::from_raw(cqe));
+            }
+            let ret = unsafe {
+                uring_sys::syscalls::io_uring_enter(
+                    self.as_raw_fd(),
+                    0,
+                    1,
+                    IORING_ENTER_GETEVENTS,
+                    std::ptr::null(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                    _ => return Err(err.into()),
+                }
+            }
+        }
+    }
+    // Submits whatever is prepared and waits up to `ts` for a completion,
+    // returning `None` if there was none by then or the wait was interrupted.
+    // `ts` is read before the call returns, so it only has to live as long
+    // as the borrow. Without EXT_ARG (5.11) the bundled liburing arms a
+    // timeout SQE for the wait, whose completion it later skips over.
+    pub fn wait_timeout(&self, ts: &mut __kernel_timespec) -> Result<Option<Cqe>> {
+        self.submit_robust()?;
+        let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
+        if !self.features.contains(RingFeature::EXT_ARG) {
+            let ret = unsafe { io_uring_wait_cqe_timeout(self._inner, &mut cqe, ts) };
+            return match -ret {
+                0 => Ok(Some(Cqe::from_raw(cqe))),
+                libc::ETIME | libc::EINTR => Ok(None),
+                errno => Err(RringError::from_errno(errno)),
+            };
+        }
+        if unsafe { io_uring_peek_cqe(self._inner, &mut cqe) } == 0 && !cqe.is_null() {
+            return Ok(Some(Cqe::from_raw(cqe)));
+        }
+        let arg = sys::io_uring_getevents_arg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            min_wait_usec: 0,
+            ts: ts as *const _ as u64,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.as_raw_fd(),
+                0,
+                1,
+                IORING_ENTER_GETEVENTS | sys::IORING_ENTER_EXT_ARG,
+                &arg as *const sys::io_uring_getevents_arg,
+                std::mem::size_of::<sys::io_uring_getevents_arg>(),
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ETIME) | Some(libc::EINTR) => {}
+                _ => return Err(err.into()),
+            }
+        }
+        if unsafe { io_uring_peek_cqe(self._inner, &mut cqe) } == 0 && !cqe.is_null() {
+            Ok(Some(Cqe::from_raw(cqe)))
+        } else {
+            Ok(None)
+        }
+    }
+    // Submits whatever is prepared and waits for `wait_nr` completions, but
+    // for no longer than `max`. Once `min` has passed the wait also ends as
+    // soon as any completion is ready, so a busy ring keeps batching for at
+    // least `min` while a quiet one still returns its first completion
+    // promptly after it. Returns the number of completions ready to reap,
+    // which is 0 if `max` ran out without any. Requires MIN_TIMEOUT.
+    pub fn wait_min_timeout(&self, wait_nr: u32, min: Duration, max: Duration) -> Result<u32> {
+        if !self.features.contains(RingFeature::MIN_TIMEOUT) {
+            return Err(RringError::from_errno(libc::EOPNOTSUPP));
+        }
+        let min_wait_usec = u32::try_from(min.as_micros())
+            .map_err(|_| RringError::invalid_input("minimum wait must fit in u32 microseconds"))?;
+        self.submit_robust()?;
+        let ts = __kernel_timespec {
+            tv_sec: max.as_secs() as i64,
+            tv_nsec: max.subsec_nanos() as i64,
+        };
+        let arg = sys::io_uring_getevents_arg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            min_wait_usec,
+            ts: &ts as *const _ as u64,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.as_raw_fd(),
+                0,
+                wait_nr,
+                IORING_ENTER_GETEVENTS | sys::IORING_ENTER_EXT_ARG,
+                &arg as *const sys::io_uring_getevents_arg,
+                std::mem::size_of::<sys::io_uring_getevents_arg>(),
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // Running out of `max` isn't an error, and restarting an
+                // interrupted wait would wait `max` all over again.
+                Some(libc::ETIME) | Some(libc::EINTR) => {}
+                _ => return Err(err.into()),
+            }
+        }
+        Ok(unsafe { io_uring_cq_ready(self._inner) })
+    }
+    // Waits for a completion, hands it to `f` and marks it seen afterwards, so
+    // the CQE can neither be leaked in the queue nor used after release.
+    pub fn wait_consume<R>(&self, f: impl FnOnce(&Cqe) -> R) -> Result<R> {
+        let cqe = self.wait_cqe()?;
+        let ret = f(&cqe);
+        self.seen(cqe);
+        Ok(ret)
+    }
+    pub fn wait_completion<T>(&self) -> Result<Completion<T>> {
+        let cqe = self.wait_cqe()?;
+        self.note_seen(&cqe);
+        let raw = cqe._inner;
+        let completion = cqe.into_completion();
+        unsafe {
+            io_uring_cqe_seen(self._inner, raw);
+        }
+        Ok(completion)
+    }
+    // Waits for a completion and releases it, reporting its outcome as a
+    // `CqeResult` (see `Cqe::into_result`).
+    pub fn wait_result(&self) -> Result<CqeResult> {
+        let cqe = self.wait_cqe()?;
+        self.note_seen(&cqe);
+        let raw = cqe._inner;
+        let result = cqe.into_result();
+        unsafe {
+            io_uring_cqe_seen(self._inner, raw);
+        }
+        Ok(result)
+    }
     pub fn seen(&self, cqe: Cqe) {
+        self.note_seen(&cqe);
         unsafe {
             io_uring_cqe_seen(self._inner, cqe._inner);
         }
     }
-    pub fn exit(&mut self) {
-        let ptr = self._inner;
+    fn note_seen(&self, cqe: &Cqe) {
+        // Messages from other rings and resource tag notifications were
+        // never submitted here, and an operation that will post more
+        // completions is still in flight.
+        if !cqe.is_msg_ring() && cqe.rsrc_tag().is_none() && !cqe.has_more() {
+            self.counters.add_completed(1);
+            if let Some(ids) = &self.ids {
+                ids.remove(cqe.user_data());
+            }
+        }
+    }
+    // Drains every completion that is ready right now, reclaiming the
+    // `UserData<T>` of each (failed operations included) and handing it to
+    // `f`. Completions without boxed user data are skipped. The CQ head is
+    // advanced once, after the last callback.
+    pub fn for_each_completion<T>(
+        &self,
+        mut f: impl FnMut(std::result::Result<Box<UserData<T>>, OperationError>),
+    ) {
+        self.drain_into(|cqe| {
+            if let Some(data) = cqe.take_data::<T>() {
+                f(data);
+            }
+        });
+    }
+    // Hands every completion that is ready right now to `f` and releases them
+    // all at once, returning how many there were. The CQE pointers are
+    // collected in a buffer owned by the ring, so draining never allocates;
+    // `f` must not drain the ring again.
+    pub fn drain_into(&self, mut f: impl FnMut(&Cqe)) -> u32 {
+        let mut buf = self.cqe_buf.borrow_mut();
+        let count =
+            unsafe { io_uring_peek_batch_cqe(self._inner, buf.as_mut_ptr(), buf.len() as u32) };
+        for &raw in &buf[..count as usize] {
+            let cqe = Cqe::from_raw(raw);
+            self.note_seen(&cqe);
+            f(&cqe);
+        }
+        unsafe {
+            io_uring_cq_advance(self._inner, count);
+        }
+        count
+    }
+    // Appends up to `max` of the completions that are ready right now to
+    // `out`, oldest first, without entering the kernel, and returns how many
+    // it added. Unlike `drain_into` the entries stay in the CQ, so they have
+    // to be released, with `seen` one by one or all at once with `advance`.
+    pub fn peek_batch(&self, out: &mut Vec<Cqe>, max: usize) -> u32 {
+        let mut buf = self.cqe_buf.borrow_mut();
+        let max = max.min(buf.len());
+        let count = unsafe { io_uring_peek_batch_cqe(self._inner, buf.as_mut_ptr(), max as u32) };
+        out.extend(buf[..count as usize].iter().map(|&raw| Cqe::from_raw(raw)));
+        count
+    }
+    // Releases the oldest `count` completions in one go, e.g. a batch taken
+    // with `peek_batch`. `count` must not exceed the number of completions
+    // seen through `peek`, `peek_batch` or `wait` and not yet released: any
+    // `Cqe` still held for the entries released here is left dangling.
+    pub fn advance(&self, count: u32) {
+        let mut buf = self.cqe_buf.borrow_mut();
+        let count = count.min(buf.len() as u32);
+        let ready = unsafe { io_uring_peek_batch_cqe(self._inner, buf.as_mut_ptr(), count) };
+        debug_assert!(ready == count, "advancing past unseen completions");
+        for &raw in &buf[..ready as usize] {
+            self.note_seen(&Cqe::from_raw(raw));
+        }
         unsafe {
-            io_uring_queue_exit(ptr);
+            io_uring_cq_advance(self._inner, ready);
         }
     }
+    // The typed counterpart of `for_each_completion`: every ready completion
+    // as a `Completion<T>`, all of them sharing the user data type `T`.
+    pub fn drain_completions<T>(&self) -> DrainCompletions<'_, T> {
+        DrainCompletions::new(self)
+    }
+    pub fn stats(&self) -> RingStats {
+        let submitted = self.counters.submitted();
+        let completed = self.counters.completed();
+        unsafe {
+            RingStats {
+                submitted,
+                completed,
+                in_flight: submitted.saturating_sub(completed),
+                sq_ready: io_uring_sq_ready(self._inner),
+                cq_ready: io_uring_cq_ready(self._inner),
+                overflow: *(*self._inner).cq.koverflow,
+            }
+        }
+    }
+    // Graceful teardown: cancels everything in flight and reaps the
+    // completions, reclaiming their `UserData<T>`, so no kernel work is left
+    // running against fds the caller is about to close. All outstanding ops
+    // must carry `UserData<T>` (or none). The cancel is repeated until it
+    // finds nothing left, rather than waiting for `stats` to show nothing in
+    // flight, which never happens after a successful CQE_SKIP_SUCCESS op.
+    // The ring itself is released when dropped.
+    pub fn shutdown<T>(&mut self) -> Result<()> {
+        // Flush whatever is queued so the cancel has an SQE and sees it all.
+        self.submit_robust()?;
+        loop {
+            let mut sqe = self.get_sqe()?;
+            sqe.cancel_all();
+            sqe.set_tag(TagKind::Shutdown, 0);
+            self.submit_robust()?;
+            let cancelled = loop {
+                let cqe = match self.wait_cqe() {
+                    Ok(cqe) => cqe,
+                    Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                    Err(e) => return Err(e),
+                };
+                let res = cqe.get_result();
+                let is_cancel =
+                    matches!(tag::decode(cqe.user_data()), Some((TagKind::Shutdown, _)));
+                drop(cqe.take_data::<T>());
+                self.seen(cqe);
+                if is_cancel {
+                    break res;
+                }
+            };
+            // The count includes operations that were already running and
+            // couldn't be stopped, so go again until nothing matches.
+            if cancelled <= 0 {
+                break;
+            }
+        }
+        self.for_each_completion::<T>(drop);
+        Ok(())
+    }
+    // Tears the ring down. Taking the ring by value means this can't run while
+    // a handle borrowing it (e.g. a `RegisteredBuffer`) is still alive, and
+    // the ring can't be used, or exited a second time, afterwards.
+    pub fn exit(self) {
+        drop(self);
+    }
+}
+
+impl AsRawFd for Rring {
+    fn as_raw_fd(&self) -> RawFd {
+        unsafe { (*self._inner).ring_fd }
+    }
 }
 
 impl Drop for Rring {
     fn drop(&mut self) {
-        self.exit();
+        // A registered fd holds a reference to the ring until the thread
+        // exits, so leaving it would keep the ring alive. From another thread
+        // there is nothing to be done about it.
+        if self.registered_index().is_some() {
+            let _ = self.unregister_ring_fd();
+        }
         unsafe {
+            io_uring_queue_exit(self._inner);
             dealloc(self._inner.cast(), self._layout);
         }
     }
@@ -138,6 +805,8 @@ impl Drop for Rring {
 
 pub struct RringParams {
     flags: SetupFlag,
+    // Part of `flags`, see `set_optional`.
+    optional: SetupFlag,
     features: RingFeature,
     sq_thread_cpu: u32,
     sq_thread_idle: u32,
@@ -147,11 +816,33 @@ impl RringParams {
     pub fn new(flags: SetupFlag, features: RingFeature) -> Self {
         Self {
             flags,
+            optional: SetupFlag::empty(),
             features,
             sq_thread_cpu: 0,
             sq_thread_idle: 0,
         }
     }
+    // Requests `flags` too, but lets `Rring::with_param` set the ring up
+    // without them where the kernel refuses them, e.g. performance hints
+    // newer than the kernel, or SQ_POLL for an unprivileged caller on a
+    // kernel without `SQ_POLL_NON_FIXED`.
+    pub fn set_optional(&mut self, flags: SetupFlag) {
+        self.flags |= flags;
+        self.optional |= flags;
+    }
+    // SQ_AFF and the SQ thread settings only make sense with SQ_POLL, and
+    // the kernel refuses SQ_AFF without it.
+    fn remove_optional(&mut self, flags: SetupFlag) {
+        let flags = if flags.contains(SetupFlag::SQ_POLL) {
+            self.sq_thread_cpu = 0;
+            self.sq_thread_idle = 0;
+            flags | SetupFlag::SQ_AFF
+        } else {
+            flags
+        };
+        self.flags.remove(flags);
+        self.optional.remove(flags);
+    }
     pub fn set_sq_thread_cpu(&mut self, val: u32) {
         if (self.flags & SetupFlag::SQ_POLL).bits() != 0 {
             self.sq_thread_cpu = val;
@@ -162,8 +853,8 @@ impl RringParams {
             self.sq_thread_idle = val;
         }
     }
-    pub unsafe fn to_raw(&self) -> io_uring_params {
-        let mut param: io_uring_params = std::mem::zeroed();
+    pub fn to_raw(&self) -> io_uring_params {
+        let mut param: io_uring_params = unsafe { std::mem::zeroed() };
         param.flags = self.flags.bits();
         param.features = self.features.bits();
         param.sq_thread_cpu = self.sq_thread_cpu;
@@ -184,17 +875,55 @@ pub enum Operation {
     Send,
     Recv,
     Accept,
+    Splice,
+    Timeout,
+    Nop,
+    Openat2,
+    Fallocate,
+    Renameat,
+    Unlinkat,
+    Mkdirat,
+    EpollWait,
+}
+
+impl Operation {
+    pub(crate) fn from_raw(raw: u64) -> Option<Self> {
+        const ALL: [Operation; 19] = [
+            Operation::Read,
+            Operation::Write,
+            Operation::Readv,
+            Operation::Writev,
+            Operation::Fsync,
+            Operation::Close,
+            Operation::Openat,
+            Operation::Send,
+            Operation::Recv,
+            Operation::Accept,
+            Operation::Splice,
+            Operation::Timeout,
+            Operation::Nop,
+            Operation::Openat2,
+            Operation::Fallocate,
+            Operation::Renameat,
+            Operation::Unlinkat,
+            Operation::Mkdirat,
+            Operation::EpollWait,
+        ];
+        ALL.into_iter().find(|&op| op as u64 == raw)
+    }
 }
 
 // using u128 so it's compatible with UUID, Ulid, etc.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Identifier(pub u128);
 
 pub struct UserData<T> {
     op: Operation,
     id: Identifier,
     srcfd: RawFd,
+    seq: u64,
     data: Option<Box<T>>,
+    waker: Option<Waker>,
 }
 
 impl<T> UserData<T> {
@@ -203,7 +932,9 @@ impl<T> UserData<T> {
             op,
             id,
             srcfd,
+            seq: 0,
             data: None,
+            waker: None,
         }
     }
     pub fn with_data(op: Operation, id: Identifier, srcfd: RawFd, data: T) -> Self {
@@ -211,7 +942,18 @@ impl<T> UserData<T> {
             op,
             id,
             srcfd,
+            seq: 0,
             data: Some(Box::new(data)),
+            waker: None,
+        }
+    }
+    // For async adapters: the task waiting on the operation, woken by the
+    // reactor with `wake` once the completion arrives. A failed operation's
+    // waker is handed on in its `OperationError`.
+    pub fn with_waker(op: Operation, id: Identifier, srcfd: RawFd, waker: Waker) -> Self {
+        Self {
+            waker: Some(waker),
+            ..Self::new(op, id, srcfd)
         }
     }
     pub fn set_data(&mut self, data: T) {
@@ -226,7 +968,532 @@ impl<T> UserData<T> {
     pub fn srcfd(&self) -> RawFd {
         self.srcfd
     }
+    // Position of the operation in submission order, assigned when the user
+    // data is attached to an SQE. The counter is shared by every ring in the
+    // process, so the numbers are monotonic but not necessarily contiguous.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+    pub(crate) fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
     pub fn data(&self) -> Option<&T> {
         self.data.as_deref()
     }
+    pub fn set_waker(&mut self, waker: Waker) {
+        self.waker = Some(waker);
+    }
+    pub fn waker(&self) -> Option<&Waker> {
+        self.waker.as_ref()
+    }
+    // Wakes the captured task, if any. Returns whether there was one.
+    pub fn wake(&mut self) -> bool {
+        self.waker.take().map(Waker::wake).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipe() -> [RawFd; 2] {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        fds
+    }
+
+    #[test]
+    fn shutdown_cancels_with_waker_and_skipped_completion_outstanding() {
+        let mut ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let fds = pipe();
+        let _waker = ring.waker().unwrap();
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.nop();
+        sqe.set_flags(SqeFlag::CQE_SKIP_SUCCESS).unwrap();
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.poll_add(fds[0], libc::POLLIN);
+        sqe.set_user_data(UserData::<()>::new(Operation::Nop, Identifier(1), fds[0]))
+            .unwrap();
+        ring.submit().unwrap();
+        ring.shutdown::<()>().unwrap();
+        assert_eq!(ring.stats().cq_ready, 0);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn refused_optional_flags_are_dropped() {
+        // The kernel refuses SQ_AFF without SQ_POLL with EINVAL.
+        let mut param = RringParams::new(SetupFlag::empty(), RingFeature::empty());
+        param.set_optional(SetupFlag::SQ_AFF);
+        let ring = Rring::with_param(8, param).unwrap();
+        assert_eq!(ring.flags(), SetupFlag::empty());
+        // Without the fallback the same request fails.
+        let param = RringParams::new(SetupFlag::SQ_AFF, RingFeature::empty());
+        assert!(Rring::with_param(8, param).is_err());
+    }
+
+    #[test]
+    fn optional_sq_poll_is_kept_where_the_kernel_allows_it() {
+        let plain = Rring::new(1, SetupFlag::empty()).unwrap();
+        if !plain.features().contains(RingFeature::SQ_POLL_NON_FIXED) {
+            return;
+        }
+        let mut param = RringParams::new(SetupFlag::empty(), RingFeature::empty());
+        param.set_optional(SetupFlag::SQ_POLL);
+        param.set_sq_thread_idle(1000);
+        let ring = Rring::with_param(8, param).unwrap();
+        assert!(ring.flags().contains(SetupFlag::SQ_POLL));
+        ring.get_sqe().unwrap().nop();
+        ring.submit().unwrap();
+        // The poll thread picks the nop up on its own; no wait is entered.
+        let start = std::time::Instant::now();
+        let cqe = loop {
+            if let Some(cqe) = ring.peek() {
+                break cqe;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::hint::spin_loop();
+        };
+        assert_eq!(cqe.get_result(), 0);
+        ring.seen(cqe);
+    }
+
+    #[test]
+    fn sequence_numbers_follow_submission_not_completion() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let fds = pipe();
+        // The read is submitted first but can only complete last.
+        let mut buf = [0u8; 1];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut buf, 1, -1);
+        sqe.set_user_data(UserData::<()>::new(Operation::Read, Identifier(0), fds[0]))
+            .unwrap();
+        for id in 1..3 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.nop();
+            sqe.set_user_data(UserData::<()>::new(Operation::Nop, Identifier(id), -1))
+                .unwrap();
+        }
+        ring.submit().unwrap();
+        let mut seqs = [0; 3];
+        let mut order = Vec::new();
+        for i in 0..3 {
+            if i == 2 {
+                assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr().cast(), 1) }, 1);
+            }
+            let cqe = ring.wait().unwrap();
+            let data = cqe.get_data::<()>().unwrap().unwrap();
+            seqs[data.id().0 as usize] = data.seq();
+            order.push(data.id().0);
+            ring.seen(cqe);
+        }
+        assert_eq!(order[2], 0);
+        assert!(seqs[0] < seqs[1] && seqs[1] < seqs[2]);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn wait_consume_releases_the_completion() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        for _ in 0..2 {
+            ring.get_sqe().unwrap().nop();
+        }
+        ring.submit_and_wait(2).unwrap();
+        assert_eq!(ring.stats().cq_ready, 2);
+        let res = ring.wait_consume(|cqe| cqe.get_result()).unwrap();
+        assert_eq!(res, 0);
+        assert_eq!(ring.stats().cq_ready, 1);
+        ring.wait_consume(|_| ()).unwrap();
+        assert_eq!(ring.stats().cq_ready, 0);
+        assert!(ring.peek().is_none());
+    }
+
+    // Queues nop, invalid op, nop, nop. The kernel fails the invalid one
+    // while preparing it, which ends a submit early unless SUBMIT_ALL is set.
+    fn prep_batch_with_invalid_op(ring: &Rring) {
+        for i in 0..4 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.nop();
+            if i == 1 {
+                sqe.set_opcode(u8::MAX);
+            }
+        }
+    }
+
+    fn reap_results(ring: &Rring, n: usize) -> Vec<i32> {
+        let mut results: Vec<_> = (0..n)
+            .map(|_| ring.wait_consume(|cqe| cqe.get_result()).unwrap())
+            .collect();
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn submit_robust_hands_over_what_a_short_submit_left_behind() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        prep_batch_with_invalid_op(&ring);
+        assert_eq!(ring.submit().unwrap(), 2);
+        assert_eq!(ring.stats().sq_ready, 2);
+        assert_eq!(ring.submit_robust().unwrap(), 2);
+        assert_eq!(ring.stats().sq_ready, 0);
+        assert_eq!(reap_results(&ring, 4), [-libc::EINVAL, 0, 0, 0]);
+
+        prep_batch_with_invalid_op(&ring);
+        assert_eq!(ring.submit_robust().unwrap(), 4);
+        assert_eq!(reap_results(&ring, 4), [-libc::EINVAL, 0, 0, 0]);
+        assert_eq!(ring.stats().in_flight, 0);
+    }
+
+    #[test]
+    fn for_each_completion_reclaims_every_box() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let data = std::rc::Rc::new(());
+        let mut buf = [0u8; 1];
+        // Even ids are nops, odd ids reads from a bad fd.
+        for id in 0..5 {
+            let mut sqe = ring.get_sqe().unwrap();
+            let op = if id % 2 == 0 {
+                sqe.nop();
+                Operation::Nop
+            } else {
+                sqe.read(-1, &mut buf, 1, 0);
+                Operation::Read
+            };
+            sqe.set_user_data(UserData::with_data(op, Identifier(id), -1, data.clone()))
+                .unwrap();
+        }
+        ring.submit_and_wait(5).unwrap();
+
+        let (mut ok, mut failed) = (Vec::new(), Vec::new());
+        ring.for_each_completion::<std::rc::Rc<()>>(|res| match res {
+            Ok(data) => ok.push(data.id().0),
+            Err(err) => {
+                assert_eq!(err.io_error().raw_os_error(), Some(libc::EBADF));
+                failed.push(err.id().unwrap().0);
+            }
+        });
+        ok.sort_unstable();
+        failed.sort_unstable();
+        assert_eq!((ok, failed), (vec![0, 2, 4], vec![1, 3]));
+        assert_eq!(std::rc::Rc::strong_count(&data), 1);
+        assert_eq!(ring.stats().in_flight, 0);
+        assert!(ring.peek().is_none());
+    }
+
+    #[test]
+    fn no_sqarray_ring_runs_nops() {
+        // Kernels before 6.6 don't know the flag.
+        let ring = match Rring::new(4, SetupFlag::NO_SQARRAY) {
+            Ok(ring) => ring,
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("{}", err),
+        };
+        // More nops than SQ entries, so the SQ wraps around.
+        for _ in 0..3 {
+            for _ in 0..3 {
+                ring.get_sqe().unwrap().nop();
+            }
+            assert_eq!(ring.submit_and_wait(3).unwrap(), 3);
+            assert_eq!(reap_results(&ring, 3), [0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn block_for_completion_loses_nothing_under_concurrent_submits() {
+        const OPS: u64 = 20_000;
+        let (submitter, completer) = Rring::new(32, SetupFlag::empty()).unwrap().split();
+        let reaper = thread::spawn(move || {
+            let mut seen = 0;
+            while seen < OPS {
+                let cqe = completer.block_for_completion().unwrap();
+                assert_eq!(cqe.get_result(), 0);
+                completer.seen(cqe);
+                seen += 1;
+            }
+            completer
+        });
+        let mut left = OPS;
+        while left > 0 {
+            match submitter.get_sqe() {
+                Ok(mut sqe) => {
+                    sqe.nop();
+                    left -= 1;
+                }
+                Err(RringError::QueueFull) => {
+                    submitter.submit().unwrap();
+                }
+                Err(err) => panic!("{}", err),
+            }
+        }
+        submitter.submit_robust().unwrap();
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || done_tx.send(reaper.join()).unwrap());
+        let completer = done_rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("a completion was lost")
+            .unwrap();
+        assert_eq!(completer.in_flight(), 0);
+    }
+
+    #[test]
+    fn submit_all_submits_past_an_invalid_op() {
+        let ring = match Rring::new(8, SetupFlag::SUBMIT_ALL) {
+            Ok(ring) => ring,
+            // Kernels before 5.18 don't know the flag.
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("{}", err),
+        };
+        prep_batch_with_invalid_op(&ring);
+        assert_eq!(ring.submit().unwrap(), 4);
+        assert_eq!(ring.stats().sq_ready, 0);
+        assert_eq!(reap_results(&ring, 4), [-libc::EINVAL, 0, 0, 0]);
+    }
+
+    #[test]
+    fn wait_result_maps_each_kind_of_completion() {
+        fn run(ring: &Rring, prep: impl FnOnce(&mut Sqe)) -> CqeResult {
+            prep(&mut ring.get_sqe().unwrap());
+            ring.submit().unwrap();
+            ring.wait_result().unwrap()
+        }
+        let mut ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        ring.tag_operations(true);
+        let (written, empty) = (pipe(), pipe());
+
+        let res = run(&ring, |sqe| sqe.write(written[1], b"abc", 3, 0));
+        assert!(matches!(res, CqeResult::BytesTransferred(3)), "{:?}", res);
+        let res = run(&ring, |sqe| {
+            sqe.openat(libc::AT_FDCWD, c"/dev/null", libc::O_RDONLY, 0)
+        });
+        assert!(matches!(res, CqeResult::NewFd(_)), "{:?}", res);
+        let res = run(&ring, |sqe| sqe.nop());
+        assert!(matches!(res, CqeResult::Success), "{:?}", res);
+        let mut ts = __kernel_timespec {
+            tv_sec: 0,
+            tv_nsec: 1_000_000,
+        };
+        let res = run(&ring, |sqe| sqe.timeout(&mut ts, 0, 0));
+        assert!(matches!(res, CqeResult::Success), "{:?}", res);
+        let mut buf = [0u8; 1];
+        match run(&ring, |sqe| sqe.read(-1, &mut buf, 1, 0)) {
+            CqeResult::Failed(err) => assert_eq!(err.raw_os_error(), Some(libc::EBADF)),
+            res => panic!("{:?}", res),
+        }
+
+        // A read that can't complete, cancelled by fd. The cancel itself
+        // isn't a tagged operation, so it comes back as well but can't be
+        // told apart.
+        ring.get_sqe().unwrap().read(empty[0], &mut buf, 1, 0);
+        let results = [
+            run(&ring, |sqe| sqe.cancel_fd(empty[0], 0)),
+            ring.wait_result().unwrap(),
+        ];
+        assert!(
+            results
+                .iter()
+                .any(|res| matches!(res, CqeResult::Cancelled)),
+            "{:?}",
+            results
+        );
+        for fd in written.iter().chain(&empty) {
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingWake(std::sync::atomic::AtomicUsize);
+
+    impl std::task::Wake for CountingWake {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn completion_wakes_the_task_in_its_user_data() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let fds = pipe();
+        let wakes = Arc::new(CountingWake::default());
+        let count = || wakes.0.load(std::sync::atomic::Ordering::SeqCst);
+        let mut buf = [0u8; 1];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut buf, 1, 0);
+        let waker = Waker::from(Arc::clone(&wakes));
+        sqe.set_user_data(UserData::<()>::with_waker(
+            Operation::Read,
+            Identifier(0),
+            fds[0],
+            waker,
+        ))
+        .unwrap();
+        ring.submit().unwrap();
+        assert!(ring.peek().is_none());
+        assert_eq!(count(), 0);
+
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr().cast(), 1) }, 1);
+        let cqe = ring.wait().unwrap();
+        let mut data = cqe.get_data::<()>().unwrap().unwrap();
+        ring.seen(cqe);
+        assert!(data.wake());
+        assert_eq!(count(), 1);
+        // The waker is gone once used.
+        assert!(!data.wake());
+        assert_eq!(count(), 1);
+
+        // A failed operation hands it on in its error.
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(-1, &mut buf, 1, 0);
+        let waker = Waker::from(Arc::clone(&wakes));
+        sqe.set_user_data(UserData::<()>::with_waker(
+            Operation::Read,
+            Identifier(0),
+            -1,
+            waker,
+        ))
+        .unwrap();
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        let mut err = cqe.get_data::<()>().err().unwrap();
+        ring.seen(cqe);
+        assert!(err.wake());
+        assert_eq!(count(), 2);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn barrier_fsync_completes_after_earlier_writes() {
+        let path = std::env::temp_dir().join(format!("rring-barrier-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let fd = file.as_raw_fd();
+        let fds = pipe();
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let tagged = |i| UserData::with_data(Operation::Write, Identifier(0), fd, i);
+
+        // A read of an empty pipe stands in for a slow earlier operation.
+        let mut byte = [0u8; 1];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.read(fds[0], &mut byte, 1, 0);
+        sqe.set_user_data(tagged(0)).unwrap();
+        for i in 1..4 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.write(fd, b"data", 4, (i - 1) * 4);
+            sqe.set_user_data(tagged(i as usize)).unwrap();
+        }
+        assert_eq!(ring.barrier_fsync(fd, 0, tagged(4)).unwrap(), 5);
+
+        let mut order: Vec<usize> = Vec::new();
+        let reap = |order: &mut Vec<usize>| {
+            while let Some(cqe) = ring.peek() {
+                assert!(cqe.get_result() >= 0);
+                let data = cqe.get_data::<usize>().unwrap().unwrap();
+                order.push(*data.data().unwrap());
+                ring.seen(cqe);
+            }
+        };
+        while order.len() < 3 {
+            ring.submit_and_wait(1).unwrap();
+            reap(&mut order);
+        }
+        // The writes are done, the fsync waits on the read.
+        order.sort_unstable();
+        assert_eq!(order, [1, 2, 3]);
+
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr().cast(), 1) }, 1);
+        while order.len() < 5 {
+            ring.submit_and_wait(1).unwrap();
+            reap(&mut order);
+        }
+        assert_eq!(order[3..], [0, 4]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 12);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn io_uring_is_available() {
+        // Every other test here needs a ring as well.
+        assert!(Rring::is_available());
+    }
+
+    #[test]
+    fn peek_on_an_empty_ring_is_none() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        assert!(ring.peek().is_none());
+        // Nor does a submitted operation show up before it completes.
+        let fds = pipe();
+        let mut buf = [0u8; 1];
+        ring.get_sqe().unwrap().read(fds[0], &mut buf, 1, 0);
+        ring.submit().unwrap();
+        assert!(ring.peek().is_none());
+
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr().cast(), 1) }, 1);
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.peek().unwrap();
+        assert_eq!(cqe.get_result(), 1);
+        ring.seen(cqe);
+        assert!(ring.peek().is_none());
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn wait_min_timeout_keeps_batching_while_completions_trickle_in() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        if !ring.features().contains(RingFeature::MIN_TIMEOUT) {
+            return;
+        }
+        let pipes: Vec<_> = (0..3).map(|_| pipe()).collect();
+        let mut bufs = [[0u8; 1]; 3];
+        for (fds, buf) in pipes.iter().zip(bufs.iter_mut()) {
+            ring.get_sqe().unwrap().read(fds[0], buf, 1, 0);
+        }
+        // One read completes every 20ms, all well within the minimum.
+        let writers: Vec<_> = pipes.iter().map(|fds| fds[1]).collect();
+        let trickle = std::thread::spawn(move || {
+            for fd in writers {
+                std::thread::sleep(Duration::from_millis(20));
+                assert_eq!(unsafe { libc::write(fd, b"x".as_ptr().cast(), 1) }, 1);
+            }
+        });
+
+        let min = Duration::from_millis(150);
+        let start = std::time::Instant::now();
+        let ready = ring
+            .wait_min_timeout(8, min, Duration::from_secs(5))
+            .unwrap();
+        let waited = start.elapsed();
+        trickle.join().unwrap();
+        // Fewer than asked for, so the wait ran to the minimum but no longer
+        // than it takes to notice the completions once it passed.
+        assert!(waited >= min, "returned after {:?}", waited);
+        assert!(
+            waited < Duration::from_secs(5),
+            "returned after {:?}",
+            waited
+        );
+        assert_eq!(ready, 3);
+        assert_eq!(reap_results(&ring, 3), [1, 1, 1]);
+
+        for fd in pipes.iter().flatten() {
+            unsafe { libc::close(*fd) };
+        }
+    }
 }
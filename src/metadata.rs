@@ -0,0 +1,113 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc::{statx, statx_timestamp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Socket,
+    Fifo,
+    BlockDevice,
+    CharDevice,
+    Unknown,
+}
+
+// The result of a `Sqe::statx` in idiomatic form. Fields the kernel didn't
+// fill in, because they weren't asked for in `mask` or the filesystem
+// doesn't track them, come back as `None`.
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    raw: statx,
+}
+
+impl Metadata {
+    pub fn from_statx(raw: &statx) -> Self {
+        Self { raw: *raw }
+    }
+    pub fn file_type(&self) -> FileType {
+        match self.raw.stx_mode as u32 & libc::S_IFMT {
+            libc::S_IFREG => FileType::File,
+            libc::S_IFDIR => FileType::Dir,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFSOCK => FileType::Socket,
+            libc::S_IFIFO => FileType::Fifo,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFCHR => FileType::CharDevice,
+            _ => FileType::Unknown,
+        }
+    }
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Dir
+    }
+    pub fn is_file(&self) -> bool {
+        self.file_type() == FileType::File
+    }
+    pub fn len(&self) -> u64 {
+        self.raw.stx_size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // Permission bits, without the file type.
+    pub fn permissions(&self) -> u32 {
+        self.raw.stx_mode as u32 & !libc::S_IFMT
+    }
+    pub fn uid(&self) -> u32 {
+        self.raw.stx_uid
+    }
+    pub fn gid(&self) -> u32 {
+        self.raw.stx_gid
+    }
+    pub fn accessed(&self) -> Option<SystemTime> {
+        self.time(libc::STATX_ATIME, &self.raw.stx_atime)
+    }
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.time(libc::STATX_MTIME, &self.raw.stx_mtime)
+    }
+    // Last status change, not to be confused with `created`.
+    pub fn changed(&self) -> Option<SystemTime> {
+        self.time(libc::STATX_CTIME, &self.raw.stx_ctime)
+    }
+    // Birth time, which not every filesystem records.
+    pub fn created(&self) -> Option<SystemTime> {
+        self.time(libc::STATX_BTIME, &self.raw.stx_btime)
+    }
+    pub fn as_raw(&self) -> &statx {
+        &self.raw
+    }
+    fn time(&self, mask: u32, ts: &statx_timestamp) -> Option<SystemTime> {
+        if self.raw.stx_mask & mask == 0 {
+            return None;
+        }
+        let nanos = Duration::new(0, ts.tv_nsec);
+        if ts.tv_sec >= 0 {
+            Some(UNIX_EPOCH + Duration::from_secs(ts.tv_sec as u64) + nanos)
+        } else {
+            Some(UNIX_EPOCH - Duration::from_secs(ts.tv_sec.unsigned_abs()) + nanos)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Rring, SetupFlag};
+
+    #[test]
+    fn statx_of_a_directory() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let meta = ring
+            .statx_metadata(libc::AT_FDCWD, c"/", libc::AT_SYMLINK_NOFOLLOW)
+            .unwrap();
+        assert_eq!(meta.file_type(), FileType::Dir);
+        assert!(meta.is_dir() && !meta.is_file());
+        // The birth time is only reported where the filesystem records it.
+        let btime = meta.as_raw().stx_mask & libc::STATX_BTIME != 0;
+        assert_eq!(meta.created().is_some(), btime);
+        if let (Some(created), Some(changed)) = (meta.created(), meta.changed()) {
+            assert!(created <= changed);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// A multishot recv or accept that keeps itself armed. The kernel ends a
+// multishot operation with a completion that lacks `IORING_CQE_F_MORE`, e.g.
+// when a recv runs out of provided buffers, and from then on nothing more
+// arrives until it is submitted again. Passing every completion to
+// `MultishotHandle::on_completion` does that resubmission.
+use std::{
+    os::unix::prelude::RawFd,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    cqe::Cqe,
+    tag::{self, TagKind},
+    Result, Rring,
+};
+
+// Tells the completions of different handles apart.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy)]
+enum MultishotOp {
+    Recv {
+        socket: RawFd,
+        bgid: u16,
+        flags: i32,
+    },
+    Accept {
+        socket: RawFd,
+        flags: i32,
+    },
+}
+
+pub struct MultishotHandle<'ring> {
+    ring: &'ring Rring,
+    op: MultishotOp,
+    user_data: u64,
+    rearms: u64,
+}
+
+impl Rring {
+    // Starts a `Sqe::recv_multishot` on `socket`, receiving into buffers of
+    // group `bgid`.
+    pub fn recv_multishot(
+        &self,
+        socket: RawFd,
+        bgid: u16,
+        flags: i32,
+    ) -> Result<MultishotHandle<'_>> {
+        MultishotHandle::new(
+            self,
+            MultishotOp::Recv {
+                socket,
+                bgid,
+                flags,
+            },
+        )
+    }
+    // Starts a `Sqe::accept_multishot` on the listening `socket`.
+    pub fn accept_multishot(&self, socket: RawFd, flags: i32) -> Result<MultishotHandle<'_>> {
+        MultishotHandle::new(self, MultishotOp::Accept { socket, flags })
+    }
+}
+
+impl<'ring> MultishotHandle<'ring> {
+    fn new(ring: &'ring Rring, op: MultishotOp) -> Result<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) & tag::PAYLOAD_MASK;
+        let handle = Self {
+            ring,
+            op,
+            user_data: tag::encode(TagKind::Multishot, id),
+            rearms: 0,
+        };
+        handle.arm()?;
+        Ok(handle)
+    }
+    fn arm(&self) -> Result<()> {
+        let mut sqe = self.ring.get_sqe()?;
+        match self.op {
+            MultishotOp::Recv {
+                socket,
+                bgid,
+                flags,
+            } => sqe.recv_multishot(socket, bgid, flags),
+            MultishotOp::Accept { socket, flags } => sqe.accept_multishot(socket, flags),
+        }
+        sqe.set_tag(TagKind::Multishot, self.user_data & tag::PAYLOAD_MASK);
+        self.ring.submit_robust()?;
+        Ok(())
+    }
+    // Whether `cqe` was posted by this handle's operation.
+    pub fn owns(&self, cqe: &Cqe) -> bool {
+        cqe.user_data() == self.user_data
+    }
+    // Re-arms the operation if `cqe` is the one that ended it, and returns
+    // whether it did. An operation that failed with anything but ENOBUFS,
+    // ECANCELED after `cancel` included, is left stopped. A recv re-armed
+    // while its group is still empty fails again right away, so buffers
+    // should be recycled before handing over the completion that ended it.
+    pub fn on_completion(&mut self, cqe: &Cqe) -> Result<bool> {
+        if !self.owns(cqe) || cqe.has_more() {
+            return Ok(false);
+        }
+        let res = cqe.get_result();
+        if res < 0 && -res != libc::ENOBUFS {
+            return Ok(false);
+        }
+        self.arm()?;
+        self.rearms += 1;
+        Ok(true)
+    }
+    // How many times the operation has been re-armed so far.
+    pub fn rearms(&self) -> u64 {
+        self.rearms
+    }
+    // Submits a cancellation of the operation, which then ends with
+    // ECANCELED. Dropping the handle leaves the operation running.
+    pub fn cancel(&self) -> Result<()> {
+        let mut sqe = self.ring.get_sqe()?;
+        sqe.cancel(self.user_data, 0);
+        self.ring.submit_robust()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Rring, SetupFlag};
+
+    fn send(fd: i32, data: &[u8]) {
+        let sent = unsafe { libc::send(fd, data.as_ptr().cast(), data.len(), 0) };
+        assert_eq!(sent, data.len() as isize);
+    }
+
+    #[test]
+    fn recv_rearms_once_its_buffers_run_out() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut fds = [0; 2];
+        let ret =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        let mut bufs = ring.register_buf_ring(2, 4, 3).unwrap();
+        let mut recv = ring.recv_multishot(fds[0], 3, 0).unwrap();
+
+        // Two sends fill both buffers, and the third finds none left.
+        let mut received = Vec::new();
+        for chunk in [b"ring", b"buff"] {
+            send(fds[1], chunk);
+            let cqe = ring.wait().unwrap();
+            assert!(recv.owns(&cqe) && cqe.has_more());
+            let bid = cqe.buffer_id().unwrap();
+            received.push(bufs.buffer(bid)[..cqe.get_result() as usize].to_vec());
+            assert!(!recv.on_completion(&cqe).unwrap());
+            ring.seen(cqe);
+        }
+        send(fds[1], b"more");
+        let cqe = ring.wait().unwrap();
+        assert!(recv.owns(&cqe) && !cqe.has_more());
+        assert_eq!(cqe.get_result(), -libc::ENOBUFS);
+
+        // Replenished, the re-armed recv picks up the data left waiting.
+        for bid in 0..2 {
+            bufs.recycle(bid);
+        }
+        assert!(recv.on_completion(&cqe).unwrap());
+        ring.seen(cqe);
+        assert_eq!(recv.rearms(), 1);
+        let cqe = ring.wait().unwrap();
+        assert!(recv.owns(&cqe) && cqe.has_more());
+        let bid = cqe.buffer_id().unwrap();
+        received.push(bufs.buffer(bid)[..cqe.get_result() as usize].to_vec());
+        ring.seen(cqe);
+        assert_eq!(received.concat(), b"ringbuffmore");
+
+        recv.cancel().unwrap();
+        let cqe = ring.wait().unwrap();
+        ring.seen(cqe);
+        let cqe = ring.wait().unwrap();
+        ring.seen(cqe);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// The opcodes the running kernel supports, from `Rring::probe`. Preparing
+// an operation the kernel doesn't know only fails once it is submitted, with
+// EINVAL, so checking up front lets a caller fall back instead.
+use std::{io, ptr::NonNull, sync::OnceLock};
+
+use uring_sys::{
+    io_uring_get_probe, io_uring_get_probe_ring, io_uring_opcode_supported, io_uring_probe,
+    IoRingOp,
+};
+
+use crate::{
+    sys::{IORING_OP_EPOLL_WAIT, IORING_OP_MKDIRAT, IORING_OP_RENAMEAT, IORING_OP_UNLINKAT},
+    Operation, Result, Rring, RringError,
+};
+
+pub struct Probe {
+    raw: NonNull<io_uring_probe>,
+}
+
+impl Rring {
+    // Requires 5.6.
+    pub fn probe(&self) -> Result<Probe> {
+        let raw = unsafe { io_uring_get_probe_ring(self._inner) };
+        match NonNull::new(raw) {
+            Some(raw) => Ok(Probe { raw }),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the kernel can't be probed for opcodes (IORING_REGISTER_PROBE)",
+            )
+            .into()),
+        }
+    }
+}
+
+impl Probe {
+    pub fn supports(&self, op: Operation) -> bool {
+        self.supports_opcode(opcode(op))
+    }
+    // For operations without an `Operation`, e.g. `sys::IORING_OP_SEND_ZC`.
+    pub fn supports_opcode(&self, opcode: u8) -> bool {
+        unsafe { io_uring_opcode_supported(self.raw.as_ptr(), opcode as i32) != 0 }
+    }
+    // `supports`, as an `RringError::Unsupported` if it doesn't.
+    pub fn require(&self, op: Operation) -> Result<()> {
+        if self.supports(op) {
+            Ok(())
+        } else {
+            Err(RringError::Unsupported(op))
+        }
+    }
+}
+
+// The bundled liburing predates `io_uring_free_probe`, which is just a
+// `free` of the probe it mallocs.
+impl Drop for Probe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::free(self.raw.as_ptr().cast());
+        }
+    }
+}
+
+// Opcode support is a property of the kernel, not of a ring, so it's probed
+// once per process, the first time a completion fails with EINVAL. `None` if
+// the kernel can't be probed.
+static KERNEL_OPCODES: OnceLock<Option<[u64; 4]>> = OnceLock::new();
+
+// Whether the running kernel is known not to support `op`; false when it
+// can't be probed.
+pub(crate) fn kernel_lacks(op: Operation) -> bool {
+    let opcodes = KERNEL_OPCODES.get_or_init(|| {
+        let probe = Probe {
+            raw: NonNull::new(unsafe { io_uring_get_probe() })?,
+        };
+        let mut bits = [0u64; 4];
+        for opcode in 0..=u8::MAX {
+            if probe.supports_opcode(opcode) {
+                bits[opcode as usize / 64] |= 1 << (opcode % 64);
+            }
+        }
+        Some(bits)
+    });
+    let opcode = opcode(op);
+    opcodes.is_some_and(|bits| bits[opcode as usize / 64] & (1 << (opcode % 64)) == 0)
+}
+
+// `IoRingOp` stops at 5.7, later opcodes come from `sys`.
+fn opcode(op: Operation) -> u8 {
+    let op = match op {
+        Operation::Read => IoRingOp::IORING_OP_READ,
+        Operation::Write => IoRingOp::IORING_OP_WRITE,
+        Operation::Readv => IoRingOp::IORING_OP_READV,
+        Operation::Writev => IoRingOp::IORING_OP_WRITEV,
+        Operation::Fsync => IoRingOp::IORING_OP_FSYNC,
+        Operation::Close => IoRingOp::IORING_OP_CLOSE,
+        Operation::Openat => IoRingOp::IORING_OP_OPENAT,
+        Operation::Send => IoRingOp::IORING_OP_SEND,
+        Operation::Recv => IoRingOp::IORING_OP_RECV,
+        Operation::Accept => IoRingOp::IORING_OP_ACCEPT,
+        Operation::Splice => IoRingOp::IORING_OP_SPLICE,
+        Operation::Timeout => IoRingOp::IORING_OP_TIMEOUT,
+        Operation::Nop => IoRingOp::IORING_OP_NOP,
+        Operation::Openat2 => IoRingOp::IORING_OP_OPENAT2,
+        Operation::Fallocate => IoRingOp::IORING_OP_FALLOCATE,
+        Operation::Renameat => return IORING_OP_RENAMEAT,
+        Operation::Unlinkat => return IORING_OP_UNLINKAT,
+        Operation::Mkdirat => return IORING_OP_MKDIRAT,
+        Operation::EpollWait => return IORING_OP_EPOLL_WAIT,
+    };
+    op as u8
+}
@@ -0,0 +1,261 @@
+// An `.await`-able recv into a `BufRing`. The future doesn't reap the CQ
+// itself: whatever drives the ring passes its completions to
+// `BufRing::complete`, which hands those of `recv_buf` over to the future
+// waiting for them and wakes its task.
+use std::{
+    future::Future,
+    ops::Deref,
+    os::unix::prelude::RawFd,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    buffer::BufRing,
+    cqe::Cqe,
+    tag::{self, TagKind},
+    Result, RringError,
+};
+
+// A `recv_buf` tag carries the buffer group above this shift, so a ring can
+// tell its own completions from those of other rings, and a per-ring counter
+// below it.
+const GROUP_SHIFT: u32 = 40;
+const ID_MASK: u64 = (1 << GROUP_SHIFT) - 1;
+
+#[derive(Default)]
+pub(crate) struct RecvSlot {
+    waker: Option<Waker>,
+    done: Option<Result<Option<Received>>>,
+}
+
+// Where a completion put its data. `None` in the slot means it received
+// nothing, e.g. at end of stream, and took no buffer.
+#[derive(Clone, Copy)]
+struct Received {
+    bid: u16,
+    offset: usize,
+    len: usize,
+    // Incremental mode only: the kernel keeps filling the rest of the buffer.
+    more: bool,
+}
+
+impl BufRing<'_> {
+    // Receives from `socket` into a buffer of this ring, resolving to a guard
+    // over the received bytes that recycles the buffer when dropped. The recv
+    // is submitted on the first poll. Dropping the future before it resolves
+    // cancels the recv.
+    pub async fn recv_buf(&self, socket: RawFd) -> Result<RecvGuard<'_>> {
+        let id = self.next_recv.get();
+        self.next_recv.set((id + 1) & ID_MASK);
+        let payload = (self.bgid() as u64) << GROUP_SHIFT | id;
+        let mut sqe = self.ring.get_sqe()?;
+        sqe.recv_buf_select(socket, self.bgid(), self.buf_size(), 0);
+        sqe.set_tag(TagKind::RecvBuf, payload);
+        self.recvs.borrow_mut().insert(payload, RecvSlot::default());
+        let recv = RecvBuf {
+            bufs: self,
+            payload,
+            done: false,
+        };
+        self.ring.submit_robust()?;
+        recv.await
+    }
+    // Hands `cqe` to the `recv_buf` waiting for it and wakes its task. Every
+    // completion reaped while a `recv_buf` is pending has to go through here
+    // before it is released with `Rring::seen`; returns false, leaving the
+    // completion to the caller, if it isn't one of this ring's `recv_buf`s.
+    pub fn complete(&self, cqe: &Cqe) -> bool {
+        let payload = match tag::decode(cqe.user_data()) {
+            Some((TagKind::RecvBuf, payload)) if payload >> GROUP_SHIFT == self.bgid() as u64 => {
+                payload
+            }
+            _ => return false,
+        };
+        let res = cqe.get_result();
+        let received = if res < 0 {
+            Err(RringError::from_errno(-res))
+        } else {
+            Ok(self.resolve(cqe).map(|(bid, offset, len)| Received {
+                bid,
+                offset,
+                len,
+                more: cqe.buffer_more(),
+            }))
+        };
+        let mut recvs = self.recvs.borrow_mut();
+        match recvs.get_mut(&payload) {
+            Some(slot) => {
+                slot.done = Some(received);
+                let waker = slot.waker.take();
+                drop(recvs);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+            // The future was dropped before the recv completed.
+            None => {
+                drop(recvs);
+                if let Ok(Some(received)) = received {
+                    self.release(received);
+                }
+            }
+        }
+        true
+    }
+    fn release(&self, received: Received) {
+        if !received.more {
+            self.recycle_shared(received.bid);
+        }
+    }
+}
+
+struct RecvBuf<'a> {
+    bufs: &'a BufRing<'a>,
+    payload: u64,
+    done: bool,
+}
+
+impl<'a> Future for RecvBuf<'a> {
+    type Output = Result<RecvGuard<'a>>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let bufs = self.bufs;
+        let mut recvs = bufs.recvs.borrow_mut();
+        let slot = recvs
+            .get_mut(&self.payload)
+            .expect("recv_buf slot gone while pending");
+        match slot.done.take() {
+            Some(received) => {
+                recvs.remove(&self.payload);
+                self.done = true;
+                Poll::Ready(received.map(|received| RecvGuard { bufs, received }))
+            }
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RecvBuf<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let slot = self.bufs.recvs.borrow_mut().remove(&self.payload);
+        match slot.and_then(|slot| slot.done) {
+            Some(Ok(Some(received))) => self.bufs.release(received),
+            Some(_) => {}
+            // Still in flight. Should the recv complete before the cancel
+            // gets to it, `complete` recycles the buffer it took.
+            None => {
+                if let Ok(mut sqe) = self.bufs.ring.get_sqe() {
+                    sqe.cancel(tag::encode(TagKind::RecvBuf, self.payload), 0);
+                    let _ = self.bufs.ring.submit_robust();
+                }
+            }
+        }
+    }
+}
+
+// The bytes a `BufRing::recv_buf` received, borrowed from the ring's buffer.
+// The buffer goes back to the kernel when the guard is dropped.
+pub struct RecvGuard<'a> {
+    bufs: &'a BufRing<'a>,
+    received: Option<Received>,
+}
+
+impl Deref for RecvGuard<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self.received {
+            Some(r) => &self.bufs.buffer(r.bid)[r.offset..r.offset + r.len],
+            None => &[],
+        }
+    }
+}
+
+impl Drop for RecvGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(received) = self.received {
+            self.bufs.release(received);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::{buffer::BufRing, Rring, SetupFlag};
+
+    fn poll_once<F: Future>(fut: std::pin::Pin<&mut F>) -> Poll<F::Output> {
+        fut.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    // Reaps one completion and hands it to `bufs`, as a reactor would.
+    fn dispatch(ring: &Rring, bufs: &BufRing) {
+        let cqe = ring.wait().unwrap();
+        assert!(bufs.complete(&cqe));
+        ring.seen(cqe);
+    }
+
+    #[test]
+    fn recv_buf_recycles_its_buffer_when_the_guard_drops() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        // A single buffer, so a second recv only gets one once it's back.
+        let bufs = match ring.register_buf_ring(1, 64, 4) {
+            Ok(bufs) => bufs,
+            // Kernels before 5.19.
+            Err(_) => return,
+        };
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) },
+            0
+        );
+        let send = |msg: &[u8]| {
+            let n = unsafe { libc::send(fds[1], msg.as_ptr().cast(), msg.len(), 0) };
+            assert_eq!(n, msg.len() as isize);
+        };
+
+        let mut first = pin!(bufs.recv_buf(fds[0]));
+        assert!(poll_once(first.as_mut()).is_pending());
+        send(b"ping");
+        dispatch(&ring, &bufs);
+        let guard = match poll_once(first.as_mut()) {
+            Poll::Ready(guard) => guard.unwrap(),
+            Poll::Pending => panic!("recv_buf still pending after its completion"),
+        };
+        assert_eq!(&*guard, b"ping");
+
+        // The only buffer is still held.
+        send(b"pong");
+        let mut second = pin!(bufs.recv_buf(fds[0]));
+        assert!(poll_once(second.as_mut()).is_pending());
+        dispatch(&ring, &bufs);
+        match poll_once(second.as_mut()) {
+            Poll::Ready(Err(err)) => assert_eq!(err.raw_os_error(), Some(libc::ENOBUFS)),
+            _ => panic!("recv_buf got a buffer that was still held"),
+        }
+
+        drop(guard);
+        let mut third = pin!(bufs.recv_buf(fds[0]));
+        assert!(poll_once(third.as_mut()).is_pending());
+        dispatch(&ring, &bufs);
+        match poll_once(third.as_mut()) {
+            Poll::Ready(guard) => assert_eq!(&*guard.unwrap(), b"pong"),
+            Poll::Pending => panic!("recv_buf still pending after its completion"),
+        }
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
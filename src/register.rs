@@ -0,0 +1,497 @@
+use std::{
+    io::{self, IoSlice},
+    mem::size_of,
+    os::unix::prelude::{AsRawFd, RawFd},
+    ptr::addr_of_mut,
+    thread,
+};
+
+use nix::sched::CpuSet;
+use uring_sys::{
+    io_uring_register_buffers, io_uring_register_eventfd, io_uring_register_eventfd_async,
+    io_uring_register_files, io_uring_unregister_eventfd, io_uring_unregister_files,
+    syscalls::io_uring_register,
+};
+
+use crate::{
+    buffer::{BufRing, BufferPool, RegisteredBuffer, RegisteredBuffers},
+    sys::{
+        io_uring_clock_register, io_uring_napi, io_uring_rsrc_register, io_uring_rsrc_update,
+        io_uring_rsrc_update2, IORING_REGISTER_BUFFERS_UPDATE, IORING_REGISTER_CLOCK,
+        IORING_REGISTER_FILES2, IORING_REGISTER_FILES_UPDATE2, IORING_REGISTER_IOWQ_AFF,
+        IORING_REGISTER_NAPI, IORING_REGISTER_RING_FDS, IORING_RSRC_REGISTER_SPARSE,
+        IORING_UNREGISTER_IOWQ_AFF, IORING_UNREGISTER_NAPI, IORING_UNREGISTER_RING_FDS,
+    },
+    tag::{self, TagKind},
+    Result, Rring, RringError,
+};
+
+// Limits the kernel puts on a registered buffer table, see
+// `io_uring/rsrc.c`.
+const MAX_REG_BUFFERS: u32 = 1 << 14;
+const MAX_REG_BUFFER_SIZE: usize = 1 << 30;
+
+impl Rring {
+    // Registration opcodes newer than the bundled liburing go through the raw
+    // syscall, which reports failures through errno.
+    pub(crate) fn register_raw(
+        &self,
+        opcode: u32,
+        arg: *mut libc::c_void,
+        nr_args: u32,
+    ) -> Result<i32> {
+        let ret = unsafe { io_uring_register(self.as_raw_fd(), opcode, arg, nr_args) };
+        if ret < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(ret)
+        }
+    }
+    // Registers `fds` as the ring's fixed file table. SQEs flagged with
+    // `SqeFlag::FIXED_FILE` then name a file by its index in `fds` instead
+    // of by fd. The fds can be closed afterwards, the table holds its own
+    // references until `unregister_files`.
+    pub fn register_files(&self, fds: &[RawFd]) -> Result<()> {
+        if fds.is_empty() {
+            return Err(RringError::invalid_input(
+                "cannot register an empty fixed file table",
+            ));
+        }
+        let ret = unsafe { io_uring_register_files(self._inner, fds.as_ptr(), fds.len() as u32) };
+        match -ret {
+            0 => Ok(()),
+            libc::EBADF => Err(RringError::invalid_input(
+                "a fixed file to register is not an open fd (-1 leaves a slot empty)",
+            )),
+            libc::EBUSY => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the ring already has a fixed file table",
+            )
+            .into()),
+            errno => Err(RringError::from_errno(errno)),
+        }
+    }
+    pub fn unregister_files(&self) -> Result<()> {
+        errno_result(unsafe { io_uring_unregister_files(self._inner) })
+    }
+    // Registers `fds` as fixed files with one tag per slot. Once a tagged file
+    // has been replaced or unregistered and the kernel is done with it, a CQE
+    // carrying the tag is posted (see `Cqe::rsrc_tag`); only then is it safe
+    // to close the old fd. A tag of 0 asks for no notification, and tags are
+    // limited to 56 bits.
+    pub fn register_files_tagged(&self, fds: &[RawFd], tags: &[u64]) -> Result<()> {
+        let tags = encode_rsrc_tags(fds, tags)?;
+        let mut reg = io_uring_rsrc_register {
+            nr: fds.len() as u32,
+            data: fds.as_ptr() as u64,
+            tags: tags.as_ptr() as u64,
+            ..Default::default()
+        };
+        self.register_raw(
+            IORING_REGISTER_FILES2,
+            addr_of_mut!(reg).cast(),
+            size_of::<io_uring_rsrc_register>() as u32,
+        )?;
+        Ok(())
+    }
+    // Registers a fixed file table of `nr` empty slots, to be filled by direct
+    // opens, accepts and sockets or by `update_files_tagged`.
+    pub fn register_files_sparse(&self, nr: u32) -> Result<()> {
+        let mut reg = io_uring_rsrc_register {
+            nr,
+            flags: IORING_RSRC_REGISTER_SPARSE,
+            ..Default::default()
+        };
+        self.register_raw(
+            IORING_REGISTER_FILES2,
+            addr_of_mut!(reg).cast(),
+            size_of::<io_uring_rsrc_register>() as u32,
+        )?;
+        Ok(())
+    }
+    // Replaces the fixed files starting at slot `offset`, tagging the new
+    // ones. The tags of the files being replaced fire once they're released.
+    pub fn update_files_tagged(&self, offset: u32, fds: &[RawFd], tags: &[u64]) -> Result<u32> {
+        let tags = encode_rsrc_tags(fds, tags)?;
+        let mut update = io_uring_rsrc_update2 {
+            offset,
+            data: fds.as_ptr() as u64,
+            tags: tags.as_ptr() as u64,
+            nr: fds.len() as u32,
+            ..Default::default()
+        };
+        let updated = self.register_raw(
+            IORING_REGISTER_FILES_UPDATE2,
+            addr_of_mut!(update).cast(),
+            size_of::<io_uring_rsrc_update2>() as u32,
+        )?;
+        Ok(updated as u32)
+    }
+    // Has the kernel signal the eventfd `fd` whenever it posts a completion,
+    // so an outside poller can watch `fd` and only turn to the ring once it
+    // fires. Only one eventfd can be registered at a time.
+    pub fn register_eventfd(&self, fd: RawFd) -> Result<()> {
+        errno_result(unsafe { io_uring_register_eventfd(self._inner, fd) })
+    }
+    // `register_eventfd`, but only completions of operations that finished
+    // asynchronously signal `fd`, not those that completed inline.
+    pub fn register_eventfd_async(&self, fd: RawFd) -> Result<()> {
+        errno_result(unsafe { io_uring_register_eventfd_async(self._inner, fd) })
+    }
+    pub fn unregister_eventfd(&self) -> Result<()> {
+        errno_result(unsafe { io_uring_unregister_eventfd(self._inner) })
+    }
+    // Enables NAPI busy polling for the sockets used on this ring (6.9+).
+    // `busy_poll_to` is the busy poll timeout in microseconds.
+    pub fn register_napi(&self, busy_poll_to: u32, prefer_busy_poll: bool) -> Result<()> {
+        let mut napi = io_uring_napi {
+            busy_poll_to,
+            prefer_busy_poll: prefer_busy_poll as u8,
+            ..Default::default()
+        };
+        self.register_raw(IORING_REGISTER_NAPI, addr_of_mut!(napi).cast(), 1)?;
+        Ok(())
+    }
+    pub fn unregister_napi(&self) -> Result<()> {
+        let mut napi = io_uring_napi::default();
+        self.register_raw(IORING_UNREGISTER_NAPI, addr_of_mut!(napi).cast(), 1)?;
+        Ok(())
+    }
+    // Restricts the async worker threads of this ring to `cpus` (5.14+),
+    // e.g. to keep them on the NUMA node that holds the data.
+    pub fn register_iowq_affinity(&self, cpus: &CpuSet) -> Result<()> {
+        self.register_raw(
+            IORING_REGISTER_IOWQ_AFF,
+            (cpus as *const CpuSet).cast_mut().cast(),
+            size_of::<CpuSet>() as u32,
+        )?;
+        Ok(())
+    }
+    // Lets the workers run anywhere again.
+    pub fn unregister_iowq_affinity(&self) -> Result<()> {
+        self.register_raw(IORING_UNREGISTER_IOWQ_AFF, std::ptr::null_mut(), 0)?;
+        Ok(())
+    }
+    // Registers the ring's own fd with the kernel (5.18+), returning its
+    // index. From then on `submit` and `submit_and_wait` enter the kernel
+    // through the index, which skips looking up the fd on every call. The
+    // registration belongs to the calling thread, so submissions from any
+    // other thread keep using the plain fd.
+    pub fn register_ring_fd(&mut self) -> Result<u32> {
+        if let Some((_, index)) = self.registered_fd {
+            return Ok(index);
+        }
+        let mut update = io_uring_rsrc_update {
+            // Lets the kernel pick the index.
+            offset: u32::MAX,
+            data: self.as_raw_fd() as u64,
+            ..Default::default()
+        };
+        self.register_raw(IORING_REGISTER_RING_FDS, addr_of_mut!(update).cast(), 1)?;
+        self.registered_fd = Some((thread::current().id(), update.offset));
+        Ok(update.offset)
+    }
+    // Has to run on the thread that registered the fd, the index means
+    // nothing anywhere else.
+    pub fn unregister_ring_fd(&mut self) -> Result<()> {
+        if let Some((owner, index)) = self.registered_fd {
+            if owner != thread::current().id() {
+                return Err(RringError::invalid_input(
+                    "the ring fd was registered on another thread",
+                ));
+            }
+            let mut update = io_uring_rsrc_update {
+                offset: index,
+                ..Default::default()
+            };
+            self.register_raw(IORING_UNREGISTER_RING_FDS, addr_of_mut!(update).cast(), 1)?;
+            self.registered_fd = None;
+        }
+        Ok(())
+    }
+    // Selects the clock that timeouts and waits on this ring are measured
+    // against (6.12+): `CLOCK_MONOTONIC`, the default, or `CLOCK_BOOTTIME` to
+    // keep counting while the system is suspended.
+    pub fn register_clock(&self, clock_id: i32) -> Result<()> {
+        let mut clock = io_uring_clock_register {
+            clockid: clock_id as u32,
+            ..Default::default()
+        };
+        self.register_raw(IORING_REGISTER_CLOCK, addr_of_mut!(clock).cast(), 0)?;
+        Ok(())
+    }
+    // Sets up a provided buffer ring for group `bgid` with `nr_entries`
+    // buffers of `buf_size` bytes each. `nr_entries` must be a power of two.
+    pub fn register_buf_ring(
+        &self,
+        nr_entries: u32,
+        buf_size: usize,
+        bgid: u16,
+    ) -> Result<BufRing<'_>> {
+        BufRing::new(self, nr_entries, buf_size, bgid, false)
+    }
+    // A `register_buf_ring` in incremental mode (6.12+), where one buffer
+    // serves several completions; see `BufRing::resolve`.
+    pub fn register_buf_ring_incremental(
+        &self,
+        nr_entries: u32,
+        buf_size: usize,
+        bgid: u16,
+    ) -> Result<BufRing<'_>> {
+        BufRing::new(self, nr_entries, buf_size, bgid, true)
+    }
+    // Provides `nr` buffers of `buf_size` bytes to group `bgid`, for kernels
+    // without buffer rings. Fails with `WouldOverflow` if the group can't
+    // take them all.
+    pub fn provide_buffer_pool(
+        &self,
+        nr: u16,
+        buf_size: usize,
+        bgid: u16,
+    ) -> Result<BufferPool<'_>> {
+        BufferPool::new(self, nr, buf_size, bgid)
+    }
+    // The most buffers a table registered with `register_buffers` can hold.
+    pub fn max_registered_buffers(&self) -> u32 {
+        MAX_REG_BUFFERS
+    }
+    // The largest single buffer the kernel accepts in such a table.
+    pub fn max_registered_buffer_size(&self) -> usize {
+        MAX_REG_BUFFER_SIZE
+    }
+    // Registers `bufs` as the ring's fixed buffer table; buffer `i` is then
+    // used by passing `i` as the `buf_index` of a fixed operation. The
+    // kernel pins the memory, but it must stay allocated for as long as the
+    // table is registered. Only one table can be registered at a time.
+    pub fn register_buffers(&self, bufs: &[IoSlice]) -> Result<()> {
+        let iovecs = unsafe { std::slice::from_raw_parts(bufs.as_ptr().cast(), bufs.len()) };
+        self.register_iovecs(iovecs)
+    }
+    // `register_buffers` for buffers the returned table takes ownership of,
+    // so they can't go away while registered.
+    pub fn register_owned_buffers(&self, bufs: Vec<Vec<u8>>) -> Result<RegisteredBuffers<'_>> {
+        RegisteredBuffers::new(self, bufs)
+    }
+    // Checks `iovecs` against the kernel's limits first, so exceeding one
+    // reports which instead of a bare EINVAL.
+    pub(crate) fn register_iovecs(&self, iovecs: &[libc::iovec]) -> Result<()> {
+        if iovecs.is_empty() || iovecs.len() > MAX_REG_BUFFERS as usize {
+            return Err(RringError::invalid_input(format!(
+                "cannot register {} buffers, the kernel allows 1 to {} (IORING_MAX_REG_BUFFERS)",
+                iovecs.len(),
+                MAX_REG_BUFFERS
+            )));
+        }
+        if let Some((i, iov)) = iovecs
+            .iter()
+            .enumerate()
+            // A null, empty entry leaves its slot unused.
+            .find(|(_, iov)| {
+                (iov.iov_len == 0 && !iov.iov_base.is_null()) || iov.iov_len > MAX_REG_BUFFER_SIZE
+            })
+        {
+            return Err(RringError::invalid_input(format!(
+                "buffer {} is {} bytes, registered buffers must be 1 byte to {} bytes (1 GiB)",
+                i, iov.iov_len, MAX_REG_BUFFER_SIZE
+            )));
+        }
+        let ret =
+            unsafe { io_uring_register_buffers(self._inner, iovecs.as_ptr(), iovecs.len() as u32) };
+        match -ret {
+            0 => Ok(()),
+            libc::EBUSY => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the ring already has a registered buffer table",
+            )
+            .into()),
+            libc::ENOMEM => Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "pinning the buffers exceeds the locked memory limit (RLIMIT_MEMLOCK)",
+            )
+            .into()),
+            errno => Err(RringError::from_errno(errno)),
+        }
+    }
+    // Registers a buffer table of `nr` empty slots, to be filled one at a
+    // time with `register_huge_buffer`.
+    pub fn register_buffers_sparse(&self, nr: u32) -> Result<()> {
+        let empty = libc::iovec {
+            iov_base: std::ptr::null_mut(),
+            iov_len: 0,
+        };
+        self.register_iovecs(&vec![empty; nr as usize])
+    }
+    // Replaces the buffers of the table starting at slot `offset`; a null,
+    // empty iovec empties its slot.
+    pub(crate) fn update_iovecs(&self, offset: u32, iovecs: &[libc::iovec]) -> Result<()> {
+        let mut update = io_uring_rsrc_update2 {
+            offset,
+            data: iovecs.as_ptr() as u64,
+            nr: iovecs.len() as u32,
+            ..Default::default()
+        };
+        self.register_raw(
+            IORING_REGISTER_BUFFERS_UPDATE,
+            addr_of_mut!(update).cast(),
+            size_of::<io_uring_rsrc_update2>() as u32,
+        )?;
+        Ok(())
+    }
+    // Registers a single huge-page backed buffer of `size` bytes in slot
+    // `index` of the table from `register_buffers_sparse`, which pins far
+    // fewer pages than registering the same amount in small buffers.
+    pub fn register_huge_buffer(&self, index: u16, size: usize) -> Result<RegisteredBuffer<'_>> {
+        RegisteredBuffer::map(self, index, size, libc::MAP_HUGETLB)
+    }
+}
+
+// The bundled liburing's registration calls return -errno.
+fn errno_result(ret: i32) -> Result<()> {
+    if ret < 0 {
+        Err(RringError::from_errno(-ret))
+    } else {
+        Ok(())
+    }
+}
+
+// Resource tags come back as the `user_data` of a CQE, so they're mapped into
+// the crate's tag space to keep them apart from boxed `UserData` pointers.
+fn encode_rsrc_tags(fds: &[RawFd], tags: &[u64]) -> Result<Vec<u64>> {
+    if fds.len() != tags.len() {
+        return Err(RringError::invalid_input(
+            "every fixed file needs exactly one tag",
+        ));
+    }
+    if tags.iter().any(|&t| t & !tag::PAYLOAD_MASK != 0) {
+        return Err(RringError::invalid_input(
+            "resource tags are limited to 56 bits",
+        ));
+    }
+    Ok(tags
+        .iter()
+        .map(|&t| {
+            if t == 0 {
+                0
+            } else {
+                tag::encode(TagKind::RsrcTag, t)
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, IoSlice},
+        os::unix::prelude::AsRawFd,
+    };
+
+    use crate::{Rring, RringError, SetupFlag};
+
+    // The ring only ever leaves its thread here to show that the fd index is
+    // refused elsewhere; nothing else touches it meanwhile.
+    struct SendRing(Rring);
+    unsafe impl Send for SendRing {}
+
+    #[test]
+    fn nops_go_through_the_registered_ring_fd() {
+        let mut ring = Rring::new(16, SetupFlag::empty()).unwrap();
+        if ring.register_ring_fd().is_err() {
+            // Kernels before 5.18.
+            return;
+        }
+        // Point the plain fd at /dev/null, so only the registered index can
+        // still reach the ring.
+        let fd = ring.as_raw_fd();
+        let saved = unsafe { libc::dup(fd) };
+        let null = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY) };
+        assert_eq!(unsafe { libc::dup2(null, fd) }, fd);
+
+        for round in 0..10 {
+            for _ in 0..8 {
+                ring.get_sqe().unwrap().nop();
+            }
+            let submitted = if round % 2 == 0 {
+                ring.submit().unwrap()
+            } else {
+                ring.submit_and_wait(8).unwrap()
+            };
+            assert_eq!(submitted, 8);
+            for _ in 0..8 {
+                assert_eq!(ring.wait_consume(|cqe| cqe.get_result()).unwrap(), 0);
+            }
+        }
+        assert_eq!(ring.stats().in_flight, 0);
+
+        assert_eq!(unsafe { libc::dup2(saved, fd) }, fd);
+        unsafe {
+            libc::close(saved);
+            libc::close(null);
+        }
+    }
+
+    #[test]
+    fn ring_fd_is_unregistered_only_on_its_thread() {
+        let mut ring = Rring::new(4, SetupFlag::empty()).unwrap();
+        if ring.register_ring_fd().is_err() {
+            return;
+        }
+        let sent = SendRing(ring);
+        let SendRing(mut ring) = std::thread::spawn(move || {
+            let mut sent = sent;
+            assert!(sent.0.unregister_ring_fd().is_err());
+            sent
+        })
+        .join()
+        .unwrap();
+        ring.unregister_ring_fd().unwrap();
+        ring.get_sqe().unwrap().nop();
+        ring.submit_and_wait(1).unwrap();
+    }
+
+    #[test]
+    fn registering_past_the_buffer_cap_names_the_cap() {
+        let ring = Rring::new(4, SetupFlag::empty()).unwrap();
+        let cap = ring.max_registered_buffers();
+        let byte = [0u8; 1];
+        let bufs = vec![IoSlice::new(&byte); cap as usize + 1];
+        let err = ring.register_buffers(&bufs).unwrap_err();
+        assert!(matches!(&err, RringError::Io(e) if e.kind() == io::ErrorKind::InvalidInput));
+        let msg = err.to_string();
+        assert!(msg.contains(&cap.to_string()), "{}", msg);
+        assert!(msg.contains("IORING_MAX_REG_BUFFERS"), "{}", msg);
+        // Nothing was registered, so a table within the cap still goes in.
+        ring.register_buffers(&bufs[..1]).unwrap();
+    }
+
+    #[test]
+    fn fixed_buffers_round_trip_through_a_file() {
+        let path = std::env::temp_dir().join(format!("rring-fixed-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let fd = file.as_raw_fd();
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let out = *b"fixed buffers";
+        let mut back = [0u8; 13];
+        // Buffer 0 is written from, buffer 1 read into.
+        ring.register_buffers(&[IoSlice::new(&out), IoSlice::new(&back)])
+            .unwrap();
+
+        let written = ring.run_one(|sqe| sqe.write_fixed(fd, &out, 13, 0, 0));
+        assert_eq!(written.unwrap(), 13);
+        let read = ring.run_one(|sqe| sqe.read_fixed(fd, &mut back, 13, 0, 1));
+        assert_eq!(read.unwrap(), 13);
+        assert_eq!(back, out);
+        // A buffer outside the one at the index is refused.
+        let res = ring.run_one(|sqe| sqe.read_fixed(fd, &mut back, 13, 0, 0));
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EFAULT));
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
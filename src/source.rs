@@ -0,0 +1,90 @@
+// Lets a `mio::Poll` drive the ring. The ring's fd polls readable whenever
+// there are completions in the CQ, so a readable event means `drain_into`
+// (or `drain_completions`) has work to do. With mio's edge-triggered
+// notifications the CQ has to be drained on every event, as completions
+// left behind won't be reported again until a new one arrives.
+use std::{io, os::unix::prelude::AsRawFd};
+
+use mio::{event::Source, unix::SourceFd, Interest, Registry, Token};
+
+use crate::{Completer, Rring};
+
+impl Source for Rring {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+// The completion half polls the same fd.
+impl Source for Completer {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mio::{Events, Interest, Poll, Token};
+
+    use crate::{Rring, SetupFlag};
+
+    #[test]
+    fn completion_makes_the_ring_readable() {
+        let mut poll = Poll::new().unwrap();
+        let mut ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        poll.registry()
+            .register(&mut ring, Token(7), Interest::READABLE)
+            .unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::ZERO)).unwrap();
+        assert!(events.is_empty());
+
+        ring.get_sqe().unwrap().nop();
+        ring.submit().unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        let event = events
+            .iter()
+            .next()
+            .expect("no readiness for the completion");
+        assert_eq!(event.token(), Token(7));
+        assert!(event.is_readable());
+        assert_eq!(ring.drain_into(|cqe| assert_eq!(cqe.get_result(), 0)), 1);
+
+        poll.registry().deregister(&mut ring).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+// The two halves of a ring handed out by `Rring::split`, for a submitting
+// thread and a reaping thread. The kernel keeps the SQ and the CQ apart, so
+// each half only touches its own queue, plus the atomic counters. Either
+// half can be sent to another thread, but neither can be shared or cloned,
+// so each queue still has a single user. The ring is torn down once both
+// halves are dropped.
+//
+// Both halves hold the same `Arc<Rring>`, but the state they could both
+// reach is still partitioned. `cqe_buf` is only borrowed by the draining
+// calls (`drain_into`, `peek_batch`, `advance`), which only the completer
+// exposes, so its `RefCell` is never borrowed from two threads. `counters`
+// are atomics: the submitter adds to `submitted` and the completer to
+// `completed`, and `in_flight` only reads both. The identifier registry and
+// the retired wakers are behind their own locks.
+use std::{
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    batch::{AutoFlushRring, SubmissionBatch},
+    cqe::{Completion, Cqe, DrainCompletions, OperationError},
+    sqe::Sqe,
+    Result, RingStats, Rring, RringWaker, UserData,
+};
+
+pub struct Submitter {
+    ring: Arc<Rring>,
+}
+
+pub struct Completer {
+    ring: Arc<Rring>,
+}
+
+// Sound because the halves never use the same queue, see above.
+unsafe impl Send for Submitter {}
+unsafe impl Send for Completer {}
+
+impl Rring {
+    // With SINGLE_ISSUER the submitter has to stay on the thread that
+    // created the ring.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn split(self) -> (Submitter, Completer) {
+        let ring = Arc::new(self);
+        (
+            Submitter {
+                ring: Arc::clone(&ring),
+            },
+            Completer { ring },
+        )
+    }
+}
+
+impl Submitter {
+    pub fn get_sqe(&self) -> Result<Sqe> {
+        self.ring.get_sqe()
+    }
+    pub fn get_sqe_or_submit(&self) -> Result<Sqe> {
+        self.ring.get_sqe_or_submit()
+    }
+    pub fn submit(&self) -> Result<u32> {
+        self.ring.submit()
+    }
+    pub fn submit_robust(&self) -> Result<u32> {
+        self.ring.submit_robust()
+    }
+    pub fn sq_space_left(&self) -> u32 {
+        self.ring.sq_space_left()
+    }
+    pub fn batch(&self) -> SubmissionBatch<'_> {
+        self.ring.batch()
+    }
+    pub fn auto_flush(&self, max_pending: u32, max_delay: Option<Duration>) -> AutoFlushRring<'_> {
+        self.ring.auto_flush(max_pending, max_delay)
+    }
+    // The waker can be handed to the completion thread to stop it.
+    pub fn waker(&self) -> Result<RringWaker> {
+        self.ring.waker()
+    }
+    pub fn stats(&self) -> RingStats {
+        self.ring.stats()
+    }
+}
+
+impl AsRawFd for Submitter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring.as_raw_fd()
+    }
+}
+
+impl AsRawFd for Completer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring.as_raw_fd()
+    }
+}
+
+impl Completer {
+    pub fn wait(&self) -> Result<Cqe> {
+        self.ring.wait()
+    }
+    pub fn peek(&self) -> Option<Cqe> {
+        self.ring.peek()
+    }
+    pub fn block_for_completion(&self) -> Result<Cqe> {
+        self.ring.block_for_completion()
+    }
+    pub fn wait_consume<R>(&self, f: impl FnOnce(&Cqe) -> R) -> Result<R> {
+        self.ring.wait_consume(f)
+    }
+    pub fn wait_completion<T>(&self) -> Result<Completion<T>> {
+        self.ring.wait_completion()
+    }
+    pub fn seen(&self, cqe: Cqe) {
+        self.ring.seen(cqe)
+    }
+    pub fn for_each_completion<T>(
+        &self,
+        f: impl FnMut(std::result::Result<Box<UserData<T>>, OperationError>),
+    ) {
+        self.ring.for_each_completion(f)
+    }
+    pub fn drain_into(&self, f: impl FnMut(&Cqe)) -> u32 {
+        self.ring.drain_into(f)
+    }
+    pub fn drain_completions<T>(&self) -> DrainCompletions<'_, T> {
+        self.ring.drain_completions()
+    }
+    // `RingStats::in_flight`, without touching the SQ.
+    pub fn in_flight(&self) -> u64 {
+        let counters = &self.ring.counters;
+        counters.submitted().saturating_sub(counters.completed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{Identifier, Operation, Rring, SetupFlag, UserData};
+
+    #[test]
+    fn one_thread_submits_while_another_reaps() {
+        const OPS: u64 = 1000;
+        let (submitter, completer) = Rring::new(16, SetupFlag::empty()).unwrap().split();
+        let reaper = thread::spawn(move || {
+            let mut seen = vec![false; OPS as usize];
+            for _ in 0..OPS {
+                let completion = completer.wait_completion::<u64>().unwrap();
+                assert_eq!(completion.result.unwrap(), 0);
+                let i = *completion.user_data.unwrap().data().unwrap() as usize;
+                assert!(!seen[i], "completion {} reaped twice", i);
+                seen[i] = true;
+            }
+            assert_eq!(completer.in_flight(), 0);
+            seen
+        });
+
+        for i in 0..OPS {
+            // The CQ is twice the SQ, so back off while the reaper is behind.
+            while submitter.stats().in_flight >= 16 {
+                thread::yield_now();
+            }
+            let mut sqe = submitter.get_sqe_or_submit().unwrap();
+            sqe.nop();
+            sqe.set_user_data(UserData::with_data(Operation::Nop, Identifier(0), -1, i))
+                .unwrap();
+            submitter.submit().unwrap();
+        }
+        assert!(reaper.join().unwrap().into_iter().all(|seen| seen));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "SINGLE_ISSUER ring used from a thread other")]
+    fn single_issuer_submitter_panics_on_another_thread() {
+        let (submitter, _completer) = Rring::new(8, SetupFlag::SINGLE_ISSUER).unwrap().split();
+        let res = thread::spawn(move || {
+            submitter.get_sqe().unwrap().nop();
+            submitter.submit().unwrap();
+        })
+        .join();
+        // Rethrow the thread's own panic, message and all.
+        if let Err(panic) = res {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
@@ -1,45 +1,353 @@
+// The raw pointer arguments are only stored in the SQE and read by the kernel
+// once the entry is submitted, so the caller has to keep them valid until then.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use std::{
-    ffi::{CStr, OsStr},
+    ffi::CStr,
     io::{IoSlice, IoSliceMut},
+    ops::Range,
     os::unix::prelude::*,
+    ptr::addr_of_mut,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
-use crate::UserData;
-use libc::{epoll_event, iovec, msghdr, sockaddr, statx};
+use crate::{
+    accept::AcceptSlot,
+    buffer::{RegisteredBuffer, RegisteredBuffers},
+    cqe::Cqe,
+    ids::IdRegistry,
+    sys::{
+        IORING_ACCEPT_MULTISHOT, IORING_ASYNC_CANCEL_ALL, IORING_ASYNC_CANCEL_ANY,
+        IORING_ASYNC_CANCEL_FD, IORING_ASYNC_CANCEL_FD_FIXED, IORING_FILE_INDEX_ALLOC,
+        IORING_OP_BIND, IORING_OP_EPOLL_WAIT, IORING_OP_LISTEN, IORING_OP_MKDIRAT,
+        IORING_OP_MSG_RING, IORING_OP_RENAMEAT, IORING_OP_SEND_ZC, IORING_OP_SOCKET,
+        IORING_OP_UNLINKAT, IORING_POLL_ADD_MULTI, IORING_RECVSEND_FIXED_BUF,
+        IORING_RECV_MULTISHOT,
+    },
+    tag::{self, TagKind},
+    Operation, Result, RringError, SqeFlag, UserData,
+};
+use libc::{epoll_event, iovec, msghdr, open_how, sockaddr, statx};
 use uring_sys::*;
 
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+pub const SQE_SIZE: usize = std::mem::size_of::<io_uring_sqe>();
+
+// `flags` for `recv`/`send` (and the msg variants) that fails the operation
+// with EAGAIN when the socket isn't ready, instead of parking it in the
+// kernel until it is.
+pub const MSG_DONTWAIT: i32 = libc::MSG_DONTWAIT;
+
 pub struct Sqe {
     _inner: *mut io_uring_sqe,
+    tag_ops: bool,
+    // Set by `set_target_fixed_file`, see `tag::OP_DIRECT`.
+    direct: bool,
+    // See `Rring::track_identifiers`.
+    ids: Option<Arc<IdRegistry>>,
 }
 
 impl Sqe {
-    pub(crate) fn from_raw(raw: *mut io_uring_sqe) -> Self {
-        Self { _inner: raw }
+    pub(crate) fn from_raw(
+        raw: *mut io_uring_sqe,
+        tag_ops: bool,
+        ids: Option<Arc<IdRegistry>>,
+    ) -> Self {
+        Self {
+            _inner: raw,
+            tag_ops,
+            direct: false,
+            ids,
+        }
+    }
+    // Returns the raw `user_data` value, which identifies the operation to
+    // `cancel` for as long as it's in flight. Refused on an SQE flagged
+    // CQE_SKIP_SUCCESS: the box is reclaimed from the completion, which a
+    // successful operation wouldn't post.
+    pub fn set_user_data<T>(&mut self, user_data: UserData<T>) -> Result<u64> {
+        self.check_skip_success()?;
+        Ok(self.attach_user_data(user_data))
     }
-    pub fn set_user_data<T>(&self, user_data: UserData<T>) {
+    fn attach_user_data<T>(&mut self, mut user_data: UserData<T>) -> u64 {
+        let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+        user_data.set_seq(seq);
+        let id = user_data.id();
         let ptr: *mut UserData<T> = Box::into_raw(Box::new(user_data));
         unsafe {
             io_uring_sqe_set_data(self._inner, ptr.cast());
         }
+        if let Some(ids) = &self.ids {
+            ids.insert(ptr as u64, id, seq);
+        }
+        ptr as u64
+    }
+    // Points `user_data` at long-lived state, e.g. a connection, instead of
+    // boxing a `UserData` per operation. The SQE holds one reference to
+    // `state` until it's given back by `Cqe::get_shared_data`, so like
+    // `set_user_data` it's refused under CQE_SKIP_SUCCESS.
+    pub fn set_shared_data<T>(&mut self, state: &Arc<T>) -> Result<()> {
+        self.check_skip_success()?;
+        let ptr = Arc::into_raw(Arc::clone(state));
+        unsafe {
+            io_uring_sqe_set_data(self._inner, ptr as *mut libc::c_void);
+        }
+        Ok(())
+    }
+    // Moves the user data of a completed operation over to this SQE, e.g. to
+    // resubmit after a short read. The box now belongs to this operation, so
+    // `cqe` must not be passed to `Cqe::get_data` afterwards.
+    pub fn reuse_user_data(&mut self, cqe: &Cqe) -> Result<()> {
+        self.check_skip_success()?;
+        unsafe {
+            (*self._inner).user_data = cqe.user_data();
+        }
+        if let Some(ids) = &self.ids {
+            ids.reuse(cqe.user_data());
+        }
+        Ok(())
     }
-    pub fn read(&self, src: RawFd, buf: &mut [u8], nbytes: u32, offset: i64) {
+    fn check_skip_success(&self) -> Result<()> {
+        if self.flags().contains(SqeFlag::CQE_SKIP_SUCCESS) {
+            return Err(RringError::invalid_input(
+                "user data on a CQE_SKIP_SUCCESS operation would never be reclaimed",
+            ));
+        }
+        Ok(())
+    }
+    // `user_data` points at a `UserData` box or shared state rather than
+    // being empty or a crate tag. Prepping resets it to 0.
+    fn carries_data(&self) -> bool {
+        let user_data = unsafe { (*self._inner).user_data };
+        user_data != 0 && !tag::is_tag(user_data)
+    }
+    // The raw bytes of the prepared entry, e.g. to record a submission and
+    // replay it later with `Rring::submit_raw_sqe`.
+    pub fn to_bytes(&self) -> [u8; SQE_SIZE] {
+        let mut bytes = [0; SQE_SIZE];
+        unsafe {
+            std::ptr::copy_nonoverlapping(self._inner.cast(), bytes.as_mut_ptr(), SQE_SIZE);
+        }
+        bytes
+    }
+    pub(crate) fn copy_from_bytes(&mut self, bytes: &[u8; SQE_SIZE]) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self._inner.cast(), SQE_SIZE);
+        }
+    }
+    // Completes right away with 0. Flagged with `SqeFlag::IO_DRAIN` it only
+    // starts once everything submitted before it has completed, which makes
+    // it a barrier for the operations after it.
+    pub fn nop(&mut self) {
+        unsafe {
+            io_uring_prep_nop(self._inner);
+        }
+        self.tag_op(Operation::Nop);
+    }
+    pub fn read(&mut self, src: RawFd, buf: &mut [u8], nbytes: u32, offset: i64) {
         unsafe {
             io_uring_prep_read(self._inner, src, buf.as_mut_ptr().cast(), nbytes, offset);
         }
+        self.tag_op(Operation::Read);
+    }
+    // `read` from the fixed file at `file_index`.
+    pub fn read_fixed_file(&mut self, file_index: u32, buf: &mut [u8], nbytes: u32, offset: i64) {
+        self.read(file_index as RawFd, buf, nbytes, offset);
+        self.set_fixed_file();
     }
-    pub fn write(&self, src: RawFd, buf: &[u8], nbytes: u32, offset: i64) {
+    pub fn write(&mut self, src: RawFd, buf: &[u8], nbytes: u32, offset: i64) {
         unsafe {
             io_uring_prep_write(self._inner, src, buf.as_ptr().cast(), nbytes, offset);
         }
+        self.tag_op(Operation::Write);
+    }
+    // Writes at the end of the file, as with `O_APPEND`, even if `src` was
+    // opened without it (`RWF_APPEND`, 4.16+).
+    pub fn write_append(&mut self, src: RawFd, buf: &[u8], nbytes: u32) {
+        self.write(src, buf, nbytes, -1);
+        unsafe {
+            (*self._inner).cmd_flags.rw_flags = libc::RWF_APPEND;
+        }
+    }
+    // O_DIRECT variants of `read`/`write` that check the buffer address,
+    // length and file offset against the device's `block_size` up front
+    // instead of letting the kernel fail the operation with a bare EINVAL.
+    pub fn read_direct(
+        &mut self,
+        src: RawFd,
+        buf: &mut [u8],
+        offset: i64,
+        block_size: usize,
+    ) -> Result<()> {
+        check_direct_alignment(buf.as_ptr(), buf.len(), offset, block_size)?;
+        self.read(src, buf, buf.len() as u32, offset);
+        Ok(())
+    }
+    pub fn write_direct(
+        &mut self,
+        src: RawFd,
+        buf: &[u8],
+        offset: i64,
+        block_size: usize,
+    ) -> Result<()> {
+        check_direct_alignment(buf.as_ptr(), buf.len(), offset, block_size)?;
+        self.write(src, buf, buf.len() as u32, offset);
+        Ok(())
+    }
+    // Fixed-buffer IO for a table set up with `Rring::register_buffers`:
+    // `buf` has to lie within the buffer registered at `buf_index`, the
+    // position of that buffer in the registered slice, or the operation
+    // fails with EFAULT.
+    pub fn read_fixed(
+        &mut self,
+        src: RawFd,
+        buf: &mut [u8],
+        nbytes: u32,
+        offset: i64,
+        buf_index: u16,
+    ) {
+        unsafe {
+            io_uring_prep_read_fixed(
+                self._inner,
+                src,
+                buf.as_mut_ptr().cast(),
+                nbytes,
+                offset,
+                buf_index as i32,
+            );
+        }
+        self.tag_op(Operation::Read);
+    }
+    pub fn write_fixed(
+        &mut self,
+        src: RawFd,
+        buf: &[u8],
+        nbytes: u32,
+        offset: i64,
+        buf_index: u16,
+    ) {
+        unsafe {
+            io_uring_prep_write_fixed(
+                self._inner,
+                src,
+                buf.as_ptr().cast(),
+                nbytes,
+                offset,
+                buf_index as i32,
+            );
+        }
+        self.tag_op(Operation::Write);
+    }
+    // Fixed-buffer IO into a `RegisteredBuffer`. `nbytes` is checked against
+    // the registered region, which the kernel would otherwise fail with
+    // EFAULT.
+    pub fn read_registered(
+        &mut self,
+        src: RawFd,
+        buf: &mut RegisteredBuffer,
+        nbytes: u32,
+        offset: i64,
+    ) -> Result<()> {
+        check_registered_len(buf, nbytes)?;
+        unsafe {
+            io_uring_prep_read_fixed(
+                self._inner,
+                src,
+                buf.as_mut_slice().as_mut_ptr().cast(),
+                nbytes,
+                offset,
+                buf.index() as i32,
+            );
+        }
+        self.tag_op(Operation::Read);
+        Ok(())
+    }
+    pub fn write_registered(
+        &mut self,
+        src: RawFd,
+        buf: &RegisteredBuffer,
+        nbytes: u32,
+        offset: i64,
+    ) -> Result<()> {
+        check_registered_len(buf, nbytes)?;
+        unsafe {
+            io_uring_prep_write_fixed(
+                self._inner,
+                src,
+                buf.as_slice().as_ptr().cast(),
+                nbytes,
+                offset,
+                buf.index() as i32,
+            );
+        }
+        self.tag_op(Operation::Write);
+        Ok(())
+    }
+    // Fixed-buffer IO into buffer `index` of a `RegisteredBuffers` table,
+    // with the same length check as `read_registered`.
+    pub fn read_registered_in(
+        &mut self,
+        src: RawFd,
+        bufs: &mut RegisteredBuffers,
+        index: u16,
+        nbytes: u32,
+        offset: i64,
+    ) -> Result<()> {
+        check_table_index(bufs, index, nbytes)?;
+        unsafe {
+            io_uring_prep_read_fixed(
+                self._inner,
+                src,
+                bufs.buffer_mut(index).as_mut_ptr().cast(),
+                nbytes,
+                offset,
+                index as i32,
+            );
+        }
+        self.tag_op(Operation::Read);
+        Ok(())
+    }
+    pub fn write_registered_in(
+        &mut self,
+        src: RawFd,
+        bufs: &RegisteredBuffers,
+        index: u16,
+        nbytes: u32,
+        offset: i64,
+    ) -> Result<()> {
+        check_table_index(bufs, index, nbytes)?;
+        unsafe {
+            io_uring_prep_write_fixed(
+                self._inner,
+                src,
+                bufs.buffer(index).as_ptr().cast(),
+                nbytes,
+                offset,
+                index as i32,
+            );
+        }
+        self.tag_op(Operation::Write);
+        Ok(())
+    }
+    // `IoSlice` and `IoSliceMut` are ABI compatible with `iovec`, so `bufs`
+    // itself is handed to the kernel; like the buffers it describes, it has
+    // to stay alive until the operation completes.
+    pub fn readv(&mut self, src: RawFd, bufs: &mut [IoSliceMut], offset: i64) {
+        let iovecs =
+            unsafe { std::slice::from_raw_parts(bufs.as_ptr().cast::<iovec>(), bufs.len()) };
+        self.readv_raw(src, iovecs, offset);
     }
-    pub fn readv(&self, src: RawFd, bufs: &mut [IoSliceMut], offset: i64) {
-        let iovecs: Vec<iovec> = bufs
-            .iter_mut()
-            .map(|b| iovec {
-                iov_len: b.len(),
-                iov_base: b.as_mut_ptr().cast(),
-            })
-            .collect();
+    pub fn writev(&mut self, src: RawFd, bufs: &[IoSlice], offset: i64) {
+        let iovecs =
+            unsafe { std::slice::from_raw_parts(bufs.as_ptr().cast::<iovec>(), bufs.len()) };
+        self.writev_raw(src, iovecs, offset);
+    }
+    // Vectored IO over an iovec array that stays valid until completion,
+    // e.g. one from an `IoVecArena`.
+    pub(crate) fn readv_raw(&mut self, src: RawFd, iovecs: &[iovec], offset: i64) {
         unsafe {
             io_uring_prep_readv(
                 self._inner,
@@ -49,18 +357,9 @@ impl Sqe {
                 offset,
             );
         }
+        self.tag_op(Operation::Readv);
     }
-    pub fn writev(&self, src: RawFd, bufs: &[IoSlice], offset: i64) {
-        let iovecs: Vec<iovec> = bufs
-            .iter()
-            .map(|b| {
-                let ptr: *const u8 = b.as_ptr();
-                iovec {
-                    iov_len: b.len(),
-                    iov_base: (ptr as *mut u8).cast(),
-                }
-            })
-            .collect();
+    pub(crate) fn writev_raw(&mut self, src: RawFd, iovecs: &[iovec], offset: i64) {
         unsafe {
             io_uring_prep_writev(
                 self._inner,
@@ -70,43 +369,182 @@ impl Sqe {
                 offset,
             );
         }
+        self.tag_op(Operation::Writev);
     }
-    pub fn fsync(&self, src: RawFd, fsync_flags: u32) {
+    pub fn fsync(&mut self, src: RawFd, fsync_flags: u32) {
         unsafe {
             io_uring_prep_fsync(self._inner, src.as_raw_fd(), fsync_flags);
         }
+        self.tag_op(Operation::Fsync);
     }
-    pub fn close(&self, src: RawFd) {
+    pub fn close(&mut self, src: RawFd) {
         unsafe {
             io_uring_prep_close(self._inner, src);
         }
+        self.tag_op(Operation::Close);
+    }
+    // Closes the fixed file at `file_index`.
+    pub fn close_direct(&mut self, file_index: u32) {
+        self.close(0);
+        self.set_target_fixed_file(file_index);
+        self.tag_op(Operation::Close);
+    }
+    // Opens into the fixed file table instead of returning an fd; `file_index`
+    // works as for `accept_direct`. `O_CLOEXEC` is rejected for direct opens.
+    pub fn openat_direct(
+        &mut self,
+        dir: RawFd,
+        path: &CStr,
+        flags: i32,
+        mode: u32,
+        file_index: u32,
+    ) {
+        unsafe {
+            io_uring_prep_openat(self._inner, dir, path.as_ptr(), flags, mode);
+        }
+        self.set_target_fixed_file(file_index);
+        self.tag_op(Operation::Openat);
     }
-    pub fn openat(&self, dir: RawFd, path: &OsStr, flags: i32, mode: u32) {
+    // The kernel copies `path` when the SQE is submitted, so it only has to
+    // live until then.
+    pub fn openat(&mut self, dir: RawFd, path: &CStr, flags: i32, mode: u32) {
         unsafe {
-            let bytes = path.as_bytes();
-            let cpath = CStr::from_bytes_with_nul_unchecked(bytes);
-            io_uring_prep_openat(self._inner, dir, cpath.as_ptr(), flags, mode);
+            io_uring_prep_openat(self._inner, dir, path.as_ptr(), flags, mode);
+        }
+        self.tag_op(Operation::Openat);
+    }
+    // `openat` with an `open_how`, whose `resolve` flags (e.g.
+    // `RESOLVE_BENEATH`, `RESOLVE_NO_SYMLINKS`) restrict how `path` is
+    // looked up (5.6+). The kernel copies `path` and `how` when the SQE is
+    // submitted, so both only have to live until then.
+    pub fn openat2(&mut self, dir: RawFd, path: &CStr, how: &open_how) {
+        unsafe {
+            io_uring_prep_openat2(
+                self._inner,
+                dir,
+                path.as_ptr(),
+                (how as *const open_how).cast_mut().cast(),
+            );
         }
+        self.tag_op(Operation::Openat2);
+    }
+    // `openat` relative to the current working directory.
+    pub fn open(&mut self, path: &CStr, flags: i32, mode: u32) {
+        self.openat(libc::AT_FDCWD, path, flags, mode);
     }
-    pub fn statx(&self, dir: RawFd, path: &OsStr, flags: i32, mask: u32, buf: *mut statx) {
+    // Renames `old_path` relative to `old_dir` to `new_path` relative to
+    // `new_dir` (5.11+). `flags` takes the `RENAME_*` flags of
+    // `renameat2(2)`, e.g. `RENAME_NOREPLACE` or `RENAME_EXCHANGE`. The kernel
+    // copies both paths when the SQE is submitted.
+    pub fn renameat(
+        &mut self,
+        old_dir: RawFd,
+        old_path: &CStr,
+        new_dir: RawFd,
+        new_path: &CStr,
+        flags: u32,
+    ) {
         unsafe {
-            let bytes = path.as_bytes();
-            let cpath = CStr::from_bytes_with_nul_unchecked(bytes);
-            io_uring_prep_statx(self._inner, dir, cpath.as_ptr(), flags, mask, buf);
+            io_uring_prep_rw(
+                IORING_OP_RENAMEAT as i32,
+                self._inner,
+                old_dir,
+                old_path.as_ptr().cast(),
+                new_dir as u32,
+                new_path.as_ptr() as u64,
+            );
+            (*self._inner).cmd_flags.rw_flags = flags as i32;
         }
+        self.tag_op(Operation::Renameat);
     }
-    pub fn fadvice(&self, src: RawFd, offset: i64, len: i64, advice: i32) {
+    // Removes `path` relative to `dir` (5.11+), or the empty directory there
+    // with `AT_REMOVEDIR` in `flags`, as `unlinkat(2)` does. The kernel
+    // copies `path` when the SQE is submitted.
+    pub fn unlinkat(&mut self, dir: RawFd, path: &CStr, flags: i32) {
         unsafe {
-            io_uring_prep_fadvise(self._inner, src, offset, len, advice);
+            io_uring_prep_rw(
+                IORING_OP_UNLINKAT as i32,
+                self._inner,
+                dir,
+                path.as_ptr().cast(),
+                0,
+                0,
+            );
+            (*self._inner).cmd_flags.rw_flags = flags;
         }
+        self.tag_op(Operation::Unlinkat);
     }
-    pub fn madvice(&self, addr: &mut [u8], len: i64, advice: i32) {
+    // Creates the directory `path` relative to `dir` with `mode` (5.15+).
+    // The kernel copies `path` when the SQE is submitted.
+    pub fn mkdirat(&mut self, dir: RawFd, path: &CStr, mode: u32) {
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_MKDIRAT as i32,
+                self._inner,
+                dir,
+                path.as_ptr().cast(),
+                mode,
+                0,
+            );
+        }
+        self.tag_op(Operation::Mkdirat);
+    }
+    // `path` only has to live until submission, but `buf` until completion.
+    pub fn statx(&mut self, dir: RawFd, path: &CStr, flags: i32, mask: u32, buf: *mut statx) {
+        unsafe {
+            io_uring_prep_statx(self._inner, dir, path.as_ptr(), flags, mask, buf);
+        }
+    }
+    // Prepped by hand: uring-sys mangles the link name of its fadvise
+    // wrapper, so calling it fails to link.
+    pub fn fadvice(&mut self, src: RawFd, offset: i64, len: i64, advice: i32) {
+        unsafe {
+            io_uring_prep_rw(
+                IoRingOp::IORING_OP_FADVISE as i32,
+                self._inner,
+                src,
+                std::ptr::null(),
+                len as u32,
+                offset as u64,
+            );
+            (*self._inner).cmd_flags.fadvise_advice = advice as u32;
+        }
+    }
+    // `fadvice` over a byte range. Empty ranges are rejected rather than
+    // being passed on as a zero length, which means "to the end of the file".
+    // The kernel takes at most `u32::MAX` bytes per call here.
+    pub fn fadvise_range(&mut self, src: RawFd, range: Range<u64>, advice: i32) -> Result<()> {
+        let (offset, len) = range_offset_len(&range)?;
+        if len > u32::MAX as i64 {
+            return Err(RringError::invalid_input(format!(
+                "fadvise range {:?} spans more than 4GiB",
+                range
+            )));
+        }
+        self.fadvice(src, offset, len, advice);
+        Ok(())
+    }
+    // `fallocate(2)`; `mode` takes the `FALLOC_FL_*` flags, e.g.
+    // `FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE` or `FALLOC_FL_ZERO_RANGE`.
+    pub fn fallocate(&mut self, src: RawFd, mode: i32, offset: i64, len: i64) {
+        unsafe {
+            io_uring_prep_fallocate(self._inner, src, mode, offset, len);
+        }
+        self.tag_op(Operation::Fallocate);
+    }
+    // `fallocate` over a byte range.
+    pub fn fallocate_range(&mut self, src: RawFd, mode: i32, range: Range<u64>) -> Result<()> {
+        let (offset, len) = range_offset_len(&range)?;
+        self.fallocate(src, mode, offset, len);
+        Ok(())
+    }
+    pub fn madvice(&mut self, addr: &mut [u8], len: i64, advice: i32) {
         unsafe {
             io_uring_prep_madvise(self._inner, addr.as_mut_ptr().cast(), len, advice);
         }
     }
     pub fn splice(
-        &self,
+        &mut self,
         in_fd: RawFd,
         in_offset: i64,
         out_fd: RawFd,
@@ -114,53 +552,853 @@ impl Sqe {
         n: u32,
         flags: u32,
     ) {
+        // uring-sys' `io_uring_prep_splice` wrapper passes its arguments to
+        // liburing in the wrong order, so the SQE is filled in here instead.
         unsafe {
-            io_uring_prep_splice(self._inner, in_fd, in_offset, out_fd, out_offset, n, flags);
+            io_uring_prep_rw(
+                IoRingOp::IORING_OP_SPLICE as i32,
+                self._inner,
+                out_fd,
+                std::ptr::null(),
+                n,
+                out_offset as u64,
+            );
+            (*self._inner).addr = in_offset as u64;
+            (*self._inner).buf_index.buf_index.splice_fd_in = in_fd;
+            (*self._inner).cmd_flags.splice_flags = flags;
         }
+        self.tag_op(Operation::Splice);
     }
-    pub fn recvmsg(&self, src: RawFd, msg: *mut msghdr, flags: u32) {
+    pub fn recvmsg(&mut self, src: RawFd, msg: *mut msghdr, flags: u32) {
         unsafe {
             io_uring_prep_recvmsg(self._inner, src, msg, flags);
         }
+        self.tag_op(Operation::Recv);
     }
-    pub fn sendmsg(&self, src: RawFd, msg: *mut msghdr, flags: u32) {
+    pub fn sendmsg(&mut self, src: RawFd, msg: *mut msghdr, flags: u32) {
         unsafe {
             io_uring_prep_sendmsg(self._inner, src, msg, flags);
         }
+        self.tag_op(Operation::Send);
     }
-    pub fn recv(&self, socket: RawFd, buf: &mut [u8], len: usize, flags: i32) {
+    // Never blocks the submitting thread, but by default an operation on a
+    // socket that isn't ready is parked until data (or buffer space) shows
+    // up. Pass `MSG_DONTWAIT` in `flags` to complete with EAGAIN right away.
+    pub fn recv(&mut self, socket: RawFd, buf: &mut [u8], len: usize, flags: i32) {
         unsafe {
             io_uring_prep_recv(self._inner, socket, buf.as_mut_ptr().cast(), len, flags);
         }
+        self.tag_op(Operation::Recv);
+    }
+    // `recv` into a buffer the kernel picks from the provided buffers of
+    // group `bgid` (e.g. a `BufRing`), reading at most `len` bytes. The
+    // completion reports which buffer was used.
+    pub fn recv_buf_select(&mut self, socket: RawFd, bgid: u16, len: usize, flags: i32) {
+        unsafe {
+            io_uring_prep_recv(self._inner, socket, std::ptr::null_mut(), len, flags);
+            (*self._inner).flags |= IOSQE_BUFFER_SELECT;
+            (*self._inner).buf_index.buf_index.index_or_group = bgid;
+        }
+        self.tag_op(Operation::Recv);
+    }
+    // Has the kernel pick the buffer of an already prepared read or recv from
+    // group `bgid`, as `recv_buf_select` does.
+    pub fn select_buffer(&mut self, bgid: u16) {
+        self.add_flags(SqeFlag::BUFFER_SELECT);
+        unsafe {
+            (*self._inner).buf_index.buf_index.index_or_group = bgid;
+        }
+    }
+    // A `recv_buf_select` that stays armed (6.0+), posting a completion
+    // flagged with `IORING_CQE_F_MORE` for every buffer it fills. It stops
+    // when it fails, e.g. with ENOBUFS once group `bgid` runs dry, and has to
+    // be submitted again; `Rring::recv_multishot` takes care of that.
+    pub fn recv_multishot(&mut self, socket: RawFd, bgid: u16, flags: i32) {
+        self.recv_buf_select(socket, bgid, 0, flags);
+        unsafe {
+            (*self._inner).ioprio |= IORING_RECV_MULTISHOT;
+        }
+    }
+    // Hands `nr` buffers of `len` bytes, carved from the start of `bufs`, to
+    // group `bgid` with ids counting up from `bid`. The kernel may take fewer
+    // than `nr`, see `Cqe::provided_buffers`. `bufs` has to stay alive until
+    // the kernel is done with the buffers.
+    pub fn provide_buffers(
+        &mut self,
+        bufs: &mut [u8],
+        len: i32,
+        nr: i32,
+        bgid: u16,
+        bid: i32,
+    ) -> Result<()> {
+        check_provided_buffers(bufs.len(), len, nr, bid)?;
+        unsafe {
+            io_uring_prep_provide_buffers(
+                self._inner,
+                bufs.as_mut_ptr().cast(),
+                len,
+                nr,
+                bgid as i32,
+                bid,
+            );
+        }
+        Ok(())
+    }
+    // The SQE form of `Rring::update_files_tagged`, without tags: replaces
+    // the fixed files from slot `offset` on with `fds`, where -1 clears a
+    // slot. `fds` is read when the operation runs, so it has to stay alive
+    // until it completes; see `Cqe::files_updated` for the result.
+    pub fn files_update(&mut self, fds: &mut [RawFd], offset: u32) {
+        unsafe {
+            io_uring_prep_files_update(
+                self._inner,
+                fds.as_mut_ptr(),
+                fds.len() as u32,
+                offset as i32,
+            );
+        }
     }
-    pub fn send(&self, socket: RawFd, buf: &[u8], len: usize, flags: i32) {
+    // Takes up to `nr` buffers out of group `bgid`. The result is the number
+    // removed, or ENOENT if the group has none left.
+    pub fn remove_buffers(&mut self, nr: i32, bgid: u16) {
+        unsafe {
+            io_uring_prep_remove_buffers(self._inner, nr, bgid as i32);
+        }
+    }
+    // See `recv` for how `MSG_DONTWAIT` changes readiness handling.
+    pub fn send(&mut self, socket: RawFd, buf: &[u8], len: usize, flags: i32) {
         unsafe {
             io_uring_prep_send(self._inner, socket, buf.as_ptr().cast(), len, flags);
         }
+        self.tag_op(Operation::Send);
+    }
+    // Zero-copy send (6.0+). The operation posts two completions with the
+    // same user data: the send result, flagged with `IORING_CQE_F_MORE`, and
+    // a notification (`Cqe::is_notif`) once the kernel no longer references
+    // `buf`. Until the notification `buf` must stay untouched, and the user
+    // data is only reclaimed from it.
+    pub fn send_zc(&mut self, socket: RawFd, buf: &[u8], len: usize, flags: i32) {
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_SEND_ZC as i32,
+                self._inner,
+                socket,
+                buf.as_ptr().cast(),
+                len as u32,
+                0,
+            );
+            (*self._inner).cmd_flags.msg_flags = flags as u32;
+        }
+        self.tag_op(Operation::Send);
     }
-    pub fn accept(&self, src: RawFd, addr: *mut sockaddr, addrlen: &mut u32, flags: i32) {
+    // `send_zc` from a registered buffer, which also saves pinning the pages
+    // for every send. The same notification rules apply to `buf`.
+    pub fn send_zc_fixed(
+        &mut self,
+        socket: RawFd,
+        buf: &RegisteredBuffer,
+        len: u32,
+        flags: i32,
+    ) -> Result<()> {
+        check_registered_len(buf, len)?;
+        self.send_zc(socket, buf.as_slice(), len as usize, flags);
+        unsafe {
+            (*self._inner).ioprio |= IORING_RECVSEND_FIXED_BUF;
+            (*self._inner).buf_index.buf_index.index_or_group = buf.index();
+        }
+        self.tag_op(Operation::Send);
+        Ok(())
+    }
+    // Receives into the spare capacity of `buf`, appending to what it holds.
+    // Reserve room first; once the completion arrives, commit the received
+    // bytes with `Cqe::commit_bytes`.
+    #[cfg(feature = "bytes")]
+    pub fn recv_bytes(&mut self, socket: RawFd, buf: &mut bytes::BytesMut, flags: i32) {
+        let spare = buf.spare_capacity_mut();
+        unsafe {
+            io_uring_prep_recv(
+                self._inner,
+                socket,
+                spare.as_mut_ptr().cast(),
+                spare.len(),
+                flags,
+            );
+        }
+        self.tag_op(Operation::Recv);
+    }
+    pub fn accept(&mut self, src: RawFd, addr: *mut sockaddr, addrlen: &mut u32, flags: i32) {
         unsafe {
             io_uring_prep_accept(self._inner, src, addr, addrlen, flags);
         }
+        self.tag_op(Operation::Accept);
     }
-    pub fn connect(&self, src: RawFd, addr: *mut sockaddr, addrlen: u32) {
+    // An `accept` that stays armed (5.19+), posting a completion for every
+    // connection, without the peer addresses.
+    pub fn accept_multishot(&mut self, src: RawFd, flags: i32) {
+        unsafe {
+            io_uring_prep_accept(
+                self._inner,
+                src,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                flags,
+            );
+            (*self._inner).ioprio |= IORING_ACCEPT_MULTISHOT;
+        }
+        self.tag_op(Operation::Accept);
+    }
+    // Accepts with the peer address written into the `AcceptSlot` carried by
+    // `user_data` (a fresh one is attached if it has no data), and attaches
+    // the user data. Read the address back with `AcceptSlot::peer_addr` once
+    // `Cqe::get_data` returns the user data.
+    pub fn accept_slot(&mut self, src: RawFd, flags: i32, mut user_data: UserData<AcceptSlot>) {
+        let slot = user_data.data.get_or_insert_with(Box::default);
+        let addr = addr_of_mut!(slot.storage).cast();
+        self.accept(src, addr, &mut slot.len, flags);
+        self.attach_user_data(user_data);
+    }
+    // Accepts straight into the fixed file table: `file_index` is the slot,
+    // or `IORING_FILE_INDEX_ALLOC` to let the kernel pick one and report it
+    // as the result.
+    pub fn accept_direct(
+        &mut self,
+        src: RawFd,
+        addr: *mut sockaddr,
+        addrlen: &mut u32,
+        flags: i32,
+        file_index: u32,
+    ) {
+        self.accept(src, addr, addrlen, flags);
+        self.set_target_fixed_file(file_index);
+        self.tag_op(Operation::Accept);
+    }
+    // `accept_direct` on a listening socket that is itself a fixed file.
+    pub fn accept_fixed_direct(
+        &mut self,
+        src_index: u32,
+        addr: *mut sockaddr,
+        addrlen: &mut u32,
+        flags: i32,
+        file_index: u32,
+    ) {
+        self.accept_direct(src_index as RawFd, addr, addrlen, flags, file_index);
+        self.set_fixed_file();
+        self.tag_op(Operation::Accept);
+    }
+    pub fn connect(&mut self, src: RawFd, addr: *mut sockaddr, addrlen: u32) {
         unsafe {
             io_uring_prep_connect(self._inner, src, addr, addrlen);
         }
     }
-    pub fn epoll_ctl(&self, epfd: RawFd, src: RawFd, op: i32, ev: *mut epoll_event) {
+    pub fn socket(&mut self, domain: i32, ty: i32, protocol: i32, flags: u32) {
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_SOCKET as i32,
+                self._inner,
+                domain,
+                std::ptr::null(),
+                protocol as u32,
+                ty as u64,
+            );
+            (*self._inner).cmd_flags.rw_flags = flags as i32;
+        }
+    }
+    // Creates the socket in the fixed file table instead of returning an fd.
+    // `file_index` works as for `accept_direct`.
+    pub fn socket_direct(
+        &mut self,
+        domain: i32,
+        ty: i32,
+        protocol: i32,
+        file_index: u32,
+        flags: u32,
+    ) {
+        self.socket(domain, ty, protocol, flags);
+        self.set_target_fixed_file(file_index);
+    }
+    pub fn bind(&mut self, src: RawFd, addr: *mut sockaddr, addrlen: u32) {
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_BIND as i32,
+                self._inner,
+                src,
+                addr.cast(),
+                0,
+                addrlen as u64,
+            );
+        }
+    }
+    pub fn bind_fixed(&mut self, file_index: u32, addr: *mut sockaddr, addrlen: u32) {
+        self.bind(file_index as RawFd, addr, addrlen);
+        self.set_fixed_file();
+    }
+    pub fn listen(&mut self, src: RawFd, backlog: u32) {
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_LISTEN as i32,
+                self._inner,
+                src,
+                std::ptr::null(),
+                backlog,
+                0,
+            );
+        }
+    }
+    pub fn listen_fixed(&mut self, file_index: u32, backlog: u32) {
+        self.listen(file_index as RawFd, backlog);
+        self.set_fixed_file();
+    }
+    pub fn epoll_ctl(&mut self, epfd: RawFd, src: RawFd, op: i32, ev: *mut epoll_event) {
         unsafe {
             io_uring_prep_epoll_ctl(self._inner, epfd, src, op, ev);
         }
     }
-    pub fn poll_add(&self, src: RawFd, poll_mask: i16) {
+    // Harvests up to `events.len()` ready events of the epoll instance `epfd`
+    // through the ring (6.15+). The result is the number of events written.
+    // The kernel takes no flags for it.
+    pub fn epoll_wait(&mut self, epfd: RawFd, events: &mut [epoll_event]) {
+        let maxevents = events.len().min(i32::MAX as usize) as u32;
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_EPOLL_WAIT as i32,
+                self._inner,
+                epfd,
+                events.as_mut_ptr().cast(),
+                maxevents,
+                0,
+            );
+        }
+        self.tag_op(Operation::EpollWait);
+    }
+    pub fn poll_add(&mut self, src: RawFd, poll_mask: i16) {
         unsafe {
             io_uring_prep_poll_add(self._inner, src, poll_mask);
         }
     }
-    pub fn poll_remove<T>(&self, user_data: *mut T) {
+    // Stays armed after firing and posts a completion (flagged with
+    // `IORING_CQE_F_MORE`) for every readiness event until removed (5.13+).
+    pub fn poll_multishot(&mut self, src: RawFd, poll_mask: i16) {
+        self.poll_add(src, poll_mask);
+        unsafe {
+            (*self._inner).len = IORING_POLL_ADD_MULTI;
+        }
+    }
+    pub(crate) fn set_tag(&mut self, kind: TagKind, payload: u64) {
+        unsafe {
+            (*self._inner).user_data = tag::encode(kind, payload);
+        }
+    }
+    pub fn poll_remove<T>(&mut self, user_data: *mut T) {
         unsafe {
             io_uring_prep_poll_remove(self._inner, user_data.cast());
         }
     }
+    // Posts a completion carrying `data` and `len` to the ring behind
+    // `target`. Only the low 56 bits of `data` are delivered, the rest of the
+    // target's `user_data` marks the CQE as a message (see `Cqe::is_msg_ring`).
+    pub fn msg_ring(&mut self, target: RawFd, len: u32, data: u64) {
+        unsafe {
+            io_uring_prep_rw(
+                IORING_OP_MSG_RING as i32,
+                self._inner,
+                target,
+                std::ptr::null(),
+                len,
+                tag::encode(TagKind::MsgRing, data),
+            );
+        }
+    }
+    // Adds `flags` to the ones already set by preparing the operation (e.g.
+    // FIXED_FILE for the `*_fixed_file` variants), so set them after the
+    // prep. An operation flagged CQE_SKIP_SUCCESS posts no completion when
+    // it succeeds, so it stays counted as in flight in `Rring::stats`, and
+    // the flag is refused once user data is attached, which would leak.
+    pub fn set_flags(&mut self, flags: SqeFlag) -> Result<()> {
+        if flags.contains(SqeFlag::CQE_SKIP_SUCCESS) && self.carries_data() {
+            return Err(RringError::invalid_input(
+                "CQE_SKIP_SUCCESS would leak the user data attached to the operation",
+            ));
+        }
+        self.add_flags(flags);
+        Ok(())
+    }
+    // `set_flags` for the crate's own flags, which can't leak anything.
+    pub(crate) fn add_flags(&mut self, flags: SqeFlag) {
+        unsafe {
+            (*self._inner).flags |= flags.bits();
+        }
+    }
+    pub fn flags(&self) -> SqeFlag {
+        unsafe { SqeFlag::from_bits_truncate((*self._inner).flags) }
+    }
+    // Lets tests make the kernel fail an SQE while preparing it.
+    #[cfg(test)]
+    pub(crate) fn set_opcode(&mut self, opcode: u8) {
+        unsafe {
+            (*self._inner).opcode = opcode;
+        }
+    }
+    // Records `op` in `user_data` on rings with `Rring::tag_operations`, for
+    // operations that go without `UserData`. Has to run after the prep,
+    // which resets `user_data`.
+    fn tag_op(&mut self, op: Operation) {
+        if self.tag_ops {
+            let direct = if self.direct { tag::OP_DIRECT } else { 0 };
+            unsafe {
+                (*self._inner).user_data = tag::encode(TagKind::Op, op as u64 | direct);
+            }
+        }
+    }
+    // Starts the next SQE only once this one has completed successfully;
+    // otherwise the rest of the chain fails with ECANCELED.
+    pub fn link(&mut self) {
+        self.add_flags(SqeFlag::IO_LINK);
+    }
+    // The fd of this SQE is an index into the fixed file table.
+    fn set_fixed_file(&mut self) {
+        unsafe {
+            (*self._inner).flags |= IOSQE_FIXED_FILE;
+        }
+    }
+    // Makes a file-creating op install its result in the fixed file table,
+    // which the kernel expects as slot + 1 with 0 meaning a regular fd.
+    fn set_target_fixed_file(&mut self, file_index: u32) {
+        let file_index = if file_index == IORING_FILE_INDEX_ALLOC {
+            file_index
+        } else {
+            file_index + 1
+        };
+        unsafe {
+            (*self._inner).buf_index.buf_index.splice_fd_in = file_index as i32;
+        }
+        self.direct = true;
+    }
+    // Completes with -ETIME once `ts` has passed, or with 0 as soon as `count`
+    // other operations have completed if `count` isn't 0. `ts` is relative
+    // unless `flags` has IORING_TIMEOUT_ABS, in which case it is a
+    // CLOCK_MONOTONIC deadline. Like an iovec array, `ts` is only read by the
+    // kernel after submission, so it has to outlive the operation.
+    pub fn timeout(&mut self, ts: &mut __kernel_timespec, count: u32, flags: u32) {
+        unsafe {
+            io_uring_prep_timeout(self._inner, ts, count, flags);
+        }
+        self.tag_op(Operation::Timeout);
+    }
+    // Bounds the operation before it in the SQ, which must have been marked
+    // with `link`, so that SQE has to be prepared right before this one.
+    // Should `ts` pass first, that operation is cancelled and completes with
+    // -ECANCELED while this one completes with -ETIME; otherwise this one
+    // completes with -ECANCELED. `ts` and `flags` are as for `timeout`.
+    pub fn link_timeout(&mut self, ts: &mut __kernel_timespec, flags: u32) {
+        unsafe {
+            io_uring_prep_link_timeout(self._inner, ts, flags);
+        }
+    }
+    // Cancels the operation whose `user_data` is `user_data`, as returned
+    // by `set_user_data`. Completes with 0 once it's cancelled, -ENOENT if no
+    // such operation is in flight and -EALREADY if it is already running and
+    // can't be stopped. With `IORING_ASYNC_CANCEL_ALL` in `flags` every match
+    // is cancelled and the result is their number.
+    pub fn cancel(&mut self, user_data: u64, flags: i32) {
+        unsafe {
+            io_uring_prep_cancel(self._inner, user_data as *mut libc::c_void, flags);
+        }
+    }
+    // Like `cancel`, but matches operations on `src` instead.
+    pub fn cancel_fd(&mut self, src: RawFd, flags: u32) {
+        unsafe {
+            io_uring_prep_rw(
+                IoRingOp::IORING_OP_ASYNC_CANCEL as i32,
+                self._inner,
+                src,
+                std::ptr::null(),
+                0,
+                0,
+            );
+            (*self._inner).cmd_flags.cancel_flags = flags | IORING_ASYNC_CANCEL_FD;
+        }
+    }
+    // Cancels every operation in flight on the ring.
+    pub fn cancel_all(&mut self) {
+        unsafe {
+            io_uring_prep_rw(
+                IoRingOp::IORING_OP_ASYNC_CANCEL as i32,
+                self._inner,
+                -1,
+                std::ptr::null(),
+                0,
+                0,
+            );
+            (*self._inner).cmd_flags.cancel_flags =
+                IORING_ASYNC_CANCEL_ALL | IORING_ASYNC_CANCEL_ANY;
+        }
+    }
+    // Cancels operations on the fixed file at `file_index`. `flags` may add
+    // `IORING_ASYNC_CANCEL_ALL` to cancel every match instead of the first.
+    pub fn cancel_fixed_fd(&mut self, file_index: u32, flags: u32) {
+        unsafe {
+            io_uring_prep_rw(
+                IoRingOp::IORING_OP_ASYNC_CANCEL as i32,
+                self._inner,
+                file_index as i32,
+                std::ptr::null(),
+                0,
+                0,
+            );
+            (*self._inner).cmd_flags.cancel_flags =
+                flags | IORING_ASYNC_CANCEL_FD | IORING_ASYNC_CANCEL_FD_FIXED;
+        }
+    }
+}
+
+fn check_registered_len(buf: &RegisteredBuffer, nbytes: u32) -> Result<()> {
+    if nbytes as usize > buf.len() {
+        return Err(RringError::WouldOverflow);
+    }
+    Ok(())
+}
+
+fn check_table_index(bufs: &RegisteredBuffers, index: u16, nbytes: u32) -> Result<()> {
+    if index as usize >= bufs.len() {
+        return Err(RringError::invalid_input(format!(
+            "buffer index {} out of range for a table of {}",
+            index,
+            bufs.len()
+        )));
+    }
+    if nbytes as usize > bufs.buffer(index).len() {
+        return Err(RringError::WouldOverflow);
+    }
+    Ok(())
+}
+
+pub(crate) fn check_provided_buffers(avail: usize, len: i32, nr: i32, bid: i32) -> Result<()> {
+    if len <= 0 || nr <= 0 || bid < 0 {
+        return Err(RringError::invalid_input(format!(
+            "invalid buffer length {}, count {} or id {}",
+            len, nr, bid
+        )));
+    }
+    if len as usize * nr as usize > avail {
+        return Err(RringError::WouldOverflow);
+    }
+    // Buffer ids are 16 bits wide.
+    if bid as u32 + nr as u32 > u16::MAX as u32 + 1 {
+        return Err(RringError::invalid_input(format!(
+            "buffer ids {}..{} don't fit in 16 bits",
+            bid,
+            bid as u32 + nr as u32
+        )));
+    }
+    Ok(())
+}
+
+fn check_direct_alignment(
+    ptr: *const u8,
+    len: usize,
+    offset: i64,
+    block_size: usize,
+) -> Result<()> {
+    let invalid = |msg: String| Err(RringError::invalid_input(msg));
+    if !block_size.is_power_of_two() {
+        return invalid(format!("block size {} is not a power of two", block_size));
+    }
+    let mask = block_size - 1;
+    if ptr as usize & mask != 0 {
+        return invalid(format!(
+            "O_DIRECT buffer at {:p} is not aligned to {} bytes",
+            ptr, block_size
+        ));
+    }
+    if len & mask != 0 {
+        return invalid(format!(
+            "O_DIRECT length {} is not a multiple of {} bytes",
+            len, block_size
+        ));
+    }
+    if offset < 0 || offset as usize & mask != 0 {
+        return invalid(format!(
+            "O_DIRECT offset {} is not a multiple of {} bytes",
+            offset, block_size
+        ));
+    }
+    Ok(())
+}
+
+fn range_offset_len(range: &Range<u64>) -> Result<(i64, i64)> {
+    if range.is_empty() {
+        return Err(RringError::invalid_input(format!(
+            "empty file range {:?}",
+            range
+        )));
+    }
+    if range.end > i64::MAX as u64 {
+        return Err(RringError::invalid_input(format!(
+            "file range {:?} exceeds the maximum file offset",
+            range
+        )));
+    }
+    Ok((range.start as i64, (range.end - range.start) as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        fs,
+        io::IoSliceMut,
+        os::unix::{fs::OpenOptionsExt, prelude::AsRawFd},
+        rc::Rc,
+    };
+
+    use crate::{Identifier, Operation, Rring, RringError, SetupFlag, SqeFlag, UserData};
+
+    #[test]
+    fn skip_success_refuses_user_data() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let data = Rc::new(());
+        let user_data = || UserData::with_data(Operation::Nop, Identifier(0), -1, data.clone());
+
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.nop();
+        sqe.set_flags(SqeFlag::CQE_SKIP_SUCCESS).unwrap();
+        assert!(sqe.set_user_data(user_data()).is_err());
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.nop();
+        sqe.set_user_data(user_data()).unwrap();
+        assert!(sqe.set_flags(SqeFlag::CQE_SKIP_SUCCESS).is_err());
+        ring.submit().unwrap();
+
+        // Only the second nop posts a completion, and reclaiming its box
+        // leaves no reference behind.
+        let cqe = ring.wait().unwrap();
+        drop(cqe.get_data::<Rc<()>>().unwrap());
+        ring.seen(cqe);
+        assert!(ring.peek().is_none());
+        assert_eq!(Rc::strong_count(&data), 1);
+    }
+
+    #[test]
+    fn epoll_wait_fills_at_most_the_slice() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        if !ring.probe().unwrap().supports(Operation::EpollWait) {
+            return;
+        }
+        let mut fds = [[0; 2]; 2];
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        assert!(epfd >= 0);
+        for (i, pair) in fds.iter_mut().enumerate() {
+            assert_eq!(unsafe { libc::pipe(pair.as_mut_ptr()) }, 0);
+            let mut ev = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: i as u64,
+            };
+            assert_eq!(
+                unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, pair[0], &mut ev) },
+                0
+            );
+            assert_eq!(unsafe { libc::write(pair[1], b"x".as_ptr().cast(), 1) }, 1);
+        }
+
+        // Both pipes are readable, but a one-entry slice must bound the harvest.
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }];
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.epoll_wait(epfd, &mut events);
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.get_result(), 1);
+        ring.seen(cqe);
+        assert_eq!(
+            { events[0].events } & libc::EPOLLIN as u32,
+            libc::EPOLLIN as u32
+        );
+
+        for fd in fds.iter().flatten().chain([&epfd]) {
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    #[test]
+    fn einval_from_a_supported_op_is_not_unsupported() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.nop();
+        // An opcode past the kernel's last one, reported under an op the
+        // kernel does support.
+        sqe.set_opcode(u8::MAX);
+        sqe.set_user_data(UserData::<()>::new(Operation::Nop, Identifier(0), -1))
+            .unwrap();
+        ring.submit().unwrap();
+        let cqe = ring.wait().unwrap();
+        let err = cqe.get_data::<()>().err().unwrap();
+        ring.seen(cqe);
+        assert_eq!(err.io_error().raw_os_error(), Some(libc::EINVAL));
+        assert!(!err.is_unsupported());
+    }
+
+    // Scribbles over the stack below the caller's frame, where a temporary
+    // iovec array built while preparing the SQE would have lived.
+    #[inline(never)]
+    fn clobber_stack() {
+        let junk = [0xa5u8; 16 * 1024];
+        std::hint::black_box(&junk);
+    }
+
+    #[test]
+    fn readv_iovecs_outlive_the_prep_call() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(
+            unsafe { libc::write(fds[1], b"hello world".as_ptr().cast(), 11) },
+            11
+        );
+
+        let (mut head, mut tail) = ([0u8; 6], [0u8; 5]);
+        let mut bufs = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)];
+        ring.get_sqe().unwrap().readv(fds[0], &mut bufs, 0);
+        // The kernel only reads the iovecs at submit.
+        clobber_stack();
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.wait().unwrap();
+        assert_eq!(cqe.get_result(), 11);
+        ring.seen(cqe);
+        assert_eq!(&head, b"hello ");
+        assert_eq!(&tail, b"world");
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn linked_writes_run_in_order() {
+        let path = std::env::temp_dir().join(format!("rring-linked-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        // Both write at the file position, so whichever runs second lands
+        // after the other. The first is punted to a worker, which the second
+        // would overtake if it weren't linked.
+        for (i, chunk) in [&b"first,"[..], b"second"].into_iter().enumerate() {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.write(file.as_raw_fd(), chunk, chunk.len() as u32, -1);
+            sqe.set_user_data(UserData::with_data(Operation::Write, Identifier(0), -1, i))
+                .unwrap();
+            if i == 0 {
+                sqe.set_flags(SqeFlag::IO_LINK | SqeFlag::ASYNC).unwrap();
+            }
+        }
+        ring.submit().unwrap();
+        for expected in 0..2 {
+            let completion = ring.wait_completion::<usize>().unwrap();
+            assert!(completion.result.unwrap() > 0);
+            assert_eq!(completion.user_data.unwrap().data(), Some(&expected));
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"first,second");
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn openat_a_path_converted_to_a_cstring() {
+        let dir = std::env::temp_dir().join(format!("rring-openat-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("foo"), b"bar").unwrap();
+        let dir_file = fs::File::open(&dir).unwrap();
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+
+        // No nul in sight, the conversion adds it.
+        let path = CString::new("foo").unwrap();
+        ring.get_sqe()
+            .unwrap()
+            .openat(dir_file.as_raw_fd(), &path, libc::O_RDONLY, 0);
+        ring.submit().unwrap();
+        // The kernel has its own copy by now.
+        drop(path);
+        let cqe = ring.wait().unwrap();
+        let fd = cqe.get_result();
+        ring.seen(cqe);
+        assert!(fd >= 0, "openat failed with {}", fd);
+        let mut buf = [0u8; 8];
+        assert_eq!(unsafe { libc::read(fd, buf.as_mut_ptr().cast(), 8) }, 3);
+        assert_eq!(&buf[..3], b"bar");
+        // Interior nuls are caught by the conversion, not passed on.
+        assert!(CString::new("f\0oo").is_err());
+
+        unsafe { libc::close(fd) };
+        drop(dir_file);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fallocate_at_the_end_of_a_sparse_gigabyte() {
+        const GIB: i64 = 1 << 30;
+        let path = std::env::temp_dir().join(format!("rring-fallocate-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let fd = file.as_raw_fd();
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+
+        // Only the last 4 KiB get blocks, but the file spans the whole GiB.
+        ring.run_one(|sqe| sqe.fallocate(fd, 0, GIB - 4096, 4096))
+            .unwrap();
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.len(), GIB as u64);
+        assert!(std::os::unix::fs::MetadataExt::blocks(&meta) * 512 < GIB as u64);
+
+        // Punching the block out again keeps the size.
+        let punch = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        match ring.run_one(|sqe| sqe.fallocate(fd, punch, GIB - 4096, 4096)) {
+            Ok(_) => assert_eq!(file.metadata().unwrap().len(), GIB as u64),
+            // Not every filesystem can punch holes.
+            Err(err) => assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP)),
+        }
+
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[repr(align(4096))]
+    struct Aligned([u8; 8192]);
+
+    #[test]
+    fn write_direct_rejects_a_misaligned_buffer() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let buf = Aligned([b'd'; 8192]);
+        let reason = |res: crate::Result<()>| match res {
+            Err(RringError::Io(err)) if err.kind() == std::io::ErrorKind::InvalidInput => {
+                err.to_string()
+            }
+            _ => panic!("misaligned O_DIRECT write was accepted"),
+        };
+        // The checks run before the SQE is prepared, so it stays usable.
+        let mut sqe = ring.get_sqe().unwrap();
+        let msg = reason(sqe.write_direct(0, &buf.0[1..4097], 0, 4096));
+        assert!(msg.contains("not aligned to 4096 bytes"), "{}", msg);
+        let msg = reason(sqe.write_direct(0, &buf.0[..4000], 0, 4096));
+        assert!(msg.contains("length 4000"), "{}", msg);
+        let msg = reason(sqe.write_direct(0, &buf.0[..4096], 512, 4096));
+        assert!(msg.contains("offset 512"), "{}", msg);
+        sqe.nop();
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.wait().unwrap();
+        ring.seen(cqe);
+
+        // An aligned write goes through on a filesystem that takes O_DIRECT.
+        let path = std::env::temp_dir().join(format!("rring-direct-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&path);
+        if let Ok(file) = file {
+            let written = ring.run_one(|sqe| {
+                sqe.write_direct(file.as_raw_fd(), &buf.0[..4096], 4096, 4096)
+                    .unwrap()
+            });
+            assert_eq!(written.unwrap(), 4096);
+        }
+        let _ = fs::remove_file(&path);
+    }
 }
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Snapshot returned by `Rring::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RingStats {
+    // SQEs the kernel has consumed over the lifetime of the ring.
+    pub submitted: u64,
+    // CQEs marked as seen over the lifetime of the ring.
+    pub completed: u64,
+    // Submitted operations whose completion hasn't been seen yet.
+    pub in_flight: u64,
+    // Prepared SQEs not yet consumed by the kernel.
+    pub sq_ready: u32,
+    // CQEs waiting to be reaped.
+    pub cq_ready: u32,
+    // Completions the kernel had to drop or defer because the CQ was full.
+    pub overflow: u32,
+}
+
+// Atomic so the submit and completion halves of a split ring (see
+// `Rring::split`) can each bump their own counter from their own thread.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    submitted: AtomicU64,
+    completed: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn add_submitted(&self, n: u64) {
+        self.submitted.fetch_add(n, Ordering::Relaxed);
+    }
+    pub(crate) fn add_completed(&self, n: u64) {
+        self.completed.fetch_add(n, Ordering::Relaxed);
+    }
+    pub(crate) fn submitted(&self) -> u64 {
+        self.submitted.load(Ordering::Relaxed)
+    }
+    pub(crate) fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingStats;
+    use crate::{Rring, SetupFlag};
+
+    #[test]
+    fn stats_follow_submits_and_reaps() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        assert_eq!(ring.stats(), RingStats::default());
+        for _ in 0..5 {
+            ring.get_sqe().unwrap().nop();
+        }
+        assert_eq!(ring.stats().sq_ready, 5);
+
+        ring.submit_and_wait(5).unwrap();
+        let stats = RingStats {
+            submitted: 5,
+            in_flight: 5,
+            cq_ready: 5,
+            ..Default::default()
+        };
+        assert_eq!(ring.stats(), stats);
+
+        for _ in 0..2 {
+            let cqe = ring.wait().unwrap();
+            ring.seen(cqe);
+        }
+        let stats = RingStats {
+            completed: 2,
+            in_flight: 3,
+            cq_ready: 3,
+            ..stats
+        };
+        assert_eq!(ring.stats(), stats);
+
+        assert_eq!(ring.drain_into(|_| {}), 3);
+        let stats = RingStats {
+            completed: 5,
+            in_flight: 0,
+            cq_ready: 0,
+            ..stats
+        };
+        assert_eq!(ring.stats(), stats);
+    }
+}
@@ -0,0 +1,144 @@
+// Kernel ABI definitions that are newer than the liburing bundled with
+// `uring-sys`. See `include/uapi/linux/io_uring.h` in the kernel tree.
+#![allow(non_camel_case_types)]
+
+// sqe opcodes
+pub const IORING_OP_RENAMEAT: u8 = 35;
+pub const IORING_OP_UNLINKAT: u8 = 36;
+pub const IORING_OP_MKDIRAT: u8 = 37;
+pub const IORING_OP_MSG_RING: u8 = 40;
+pub const IORING_OP_SOCKET: u8 = 45;
+pub const IORING_OP_SEND_ZC: u8 = 47;
+pub const IORING_OP_BIND: u8 = 56;
+pub const IORING_OP_LISTEN: u8 = 57;
+pub const IORING_OP_EPOLL_WAIT: u8 = 59;
+
+// Lets the kernel pick a free slot for direct descriptors.
+pub const IORING_FILE_INDEX_ALLOC: u32 = !0;
+
+// io_uring_register(2) opcodes
+pub const IORING_REGISTER_FILES2: u32 = 13;
+pub const IORING_REGISTER_FILES_UPDATE2: u32 = 14;
+pub const IORING_REGISTER_BUFFERS_UPDATE: u32 = 16;
+pub const IORING_REGISTER_IOWQ_AFF: u32 = 17;
+pub const IORING_UNREGISTER_IOWQ_AFF: u32 = 18;
+pub const IORING_REGISTER_RING_FDS: u32 = 20;
+pub const IORING_UNREGISTER_RING_FDS: u32 = 21;
+pub const IORING_REGISTER_PBUF_RING: u32 = 22;
+pub const IORING_UNREGISTER_PBUF_RING: u32 = 23;
+pub const IORING_REGISTER_NAPI: u32 = 27;
+pub const IORING_UNREGISTER_NAPI: u32 = 28;
+pub const IORING_REGISTER_CLOCK: u32 = 29;
+
+// io_uring_rsrc_register->flags
+pub const IORING_RSRC_REGISTER_SPARSE: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_rsrc_register {
+    pub nr: u32,
+    pub flags: u32,
+    pub resv2: u64,
+    pub data: u64,
+    pub tags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_rsrc_update {
+    pub offset: u32,
+    pub resv: u32,
+    pub data: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_rsrc_update2 {
+    pub offset: u32,
+    pub resv: u32,
+    pub data: u64,
+    pub tags: u64,
+    pub nr: u32,
+    pub resv2: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_clock_register {
+    pub clockid: u32,
+    pub resv: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_buf_reg {
+    pub ring_addr: u64,
+    pub ring_entries: u32,
+    pub bgid: u16,
+    pub flags: u16,
+    pub resv: [u64; 3],
+}
+
+// io_uring_buf_reg->flags
+pub const IOU_PBUF_RING_INC: u16 = 2;
+
+// An entry of a provided buffer ring. The `resv` field of the first entry
+// doubles as the ring's tail.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_buf {
+    pub addr: u64,
+    pub len: u32,
+    pub bid: u16,
+    pub resv: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_napi {
+    pub busy_poll_to: u32,
+    pub prefer_busy_poll: u8,
+    pub opcode: u8,
+    pub pad: [u8; 2],
+    pub op_param: u32,
+    pub resv: u32,
+}
+
+// Passed in place of the sigmask to `io_uring_enter` with
+// IORING_ENTER_EXT_ARG. `min_wait_usec` was padding before IORING_FEAT_MIN_TIMEOUT.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct io_uring_getevents_arg {
+    pub sigmask: u64,
+    pub sigmask_sz: u32,
+    pub min_wait_usec: u32,
+    pub ts: u64,
+}
+
+// io_uring_enter(2) flags
+pub const IORING_ENTER_EXT_ARG: u32 = 1 << 3;
+pub const IORING_ENTER_REGISTERED_RING: u32 = 1 << 4;
+
+// sqe->ioprio for send/recv
+pub const IORING_RECV_MULTISHOT: u16 = 1 << 1;
+pub const IORING_RECVSEND_FIXED_BUF: u16 = 1 << 2;
+
+// sqe->ioprio for accept
+pub const IORING_ACCEPT_MULTISHOT: u16 = 1 << 0;
+
+// sqe->len for IORING_OP_POLL_ADD
+pub const IORING_POLL_ADD_MULTI: u32 = 1 << 0;
+
+// cqe->flags
+pub const IORING_CQE_F_BUFFER: u32 = 1 << 0;
+pub const IORING_CQE_F_MORE: u32 = 1 << 1;
+pub const IORING_CQE_F_NOTIF: u32 = 1 << 3;
+pub const IORING_CQE_F_BUF_MORE: u32 = 1 << 4;
+pub const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+pub const IORING_CQE_F_SOCK_NONEMPTY: u32 = 1 << 2;
+
+// sqe->cancel_flags
+pub const IORING_ASYNC_CANCEL_ALL: u32 = 1 << 0;
+pub const IORING_ASYNC_CANCEL_FD: u32 = 1 << 1;
+pub const IORING_ASYNC_CANCEL_ANY: u32 = 1 << 2;
+pub const IORING_ASYNC_CANCEL_FD_FIXED: u32 = 1 << 3;
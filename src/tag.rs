@@ -0,0 +1,66 @@
+// `user_data` values generated by the crate itself instead of being a pointer
+// to a boxed `UserData`. Boxed data always lives in the lower half of the
+// address space, so a set top bit can never be confused with a pointer. The
+// remaining bits of the top byte name the kind of tag, and the lower 56 bits
+// carry its payload.
+//
+// Note that liburing reserves `u64::MAX` (`LIBURING_UDATA_TIMEOUT`) for its
+// own timeouts, so the kind 0x7f must stay unused.
+
+const TAG_BIT: u64 = 1 << 63;
+const KIND_SHIFT: u32 = 56;
+const KIND_MASK: u64 = 0x7f;
+pub(crate) const PAYLOAD_MASK: u64 = (1 << KIND_SHIFT) - 1;
+// Set next to the `Operation` of an `Op` tag when the operation puts the new
+// file into the fixed file table, so its result isn't an fd.
+pub(crate) const OP_DIRECT: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum TagKind {
+    MsgRing = 1,
+    RsrcTag = 2,
+    Waker = 3,
+    Op = 4,
+    Multishot = 5,
+    RecvBuf = 6,
+    Shutdown = 7,
+    Cancel = 8,
+    PoolRemoval = 9,
+    Chain = 10,
+}
+
+impl TagKind {
+    fn from_raw(raw: u64) -> Option<Self> {
+        match raw {
+            1 => Some(Self::MsgRing),
+            2 => Some(Self::RsrcTag),
+            3 => Some(Self::Waker),
+            4 => Some(Self::Op),
+            5 => Some(Self::Multishot),
+            6 => Some(Self::RecvBuf),
+            7 => Some(Self::Shutdown),
+            8 => Some(Self::Cancel),
+            9 => Some(Self::PoolRemoval),
+            10 => Some(Self::Chain),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn encode(kind: TagKind, payload: u64) -> u64 {
+    debug_assert!(payload & !PAYLOAD_MASK == 0, "tag payload exceeds 56 bits");
+    TAG_BIT | ((kind as u64) << KIND_SHIFT) | (payload & PAYLOAD_MASK)
+}
+
+pub(crate) fn is_tag(user_data: u64) -> bool {
+    user_data & TAG_BIT != 0
+}
+
+pub(crate) fn decode(user_data: u64) -> Option<(TagKind, u64)> {
+    if !is_tag(user_data) {
+        return None;
+    }
+    let kind = TagKind::from_raw((user_data >> KIND_SHIFT) & KIND_MASK)?;
+    Some((kind, user_data & PAYLOAD_MASK))
+}
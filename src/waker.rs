@@ -0,0 +1,170 @@
+use std::{
+    io,
+    os::unix::prelude::RawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    tag::{self, TagKind},
+    Result, Rring,
+};
+
+// Wakes a thread blocked in `Rring::wait` from anywhere, e.g. to stop an IO
+// loop. The ring keeps a multishot poll armed on an eventfd, so every
+// `wake` makes `wait` return a completion for which `Cqe::is_wake` is true.
+// Unlike the ring itself, the waker can be cloned and sent across threads.
+// Once the last clone is dropped the poll is cancelled with the ring's next
+// submit; its final completion, ECANCELED, still counts as a wake.
+#[derive(Clone)]
+pub struct RringWaker {
+    fd: Arc<EventFd>,
+}
+
+struct EventFd {
+    fd: RawFd,
+    // Tag payload of the poll, unique per ring.
+    id: u64,
+    retired: Arc<RetiredWakers>,
+}
+
+// The polls of dropped wakers. The last clone can go away on any thread,
+// while only the submitting thread may touch the SQ, so the drop just queues
+// the poll here and `Rring::submit` cancels it.
+#[derive(Default)]
+pub(crate) struct RetiredWakers {
+    next_id: AtomicU64,
+    pending: AtomicBool,
+    ids: Mutex<Vec<u64>>,
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        // The armed poll holds its own reference to the file, so closing it
+        // first is fine.
+        unsafe {
+            libc::close(self.fd);
+        }
+        self.retired.ids.lock().unwrap().push(self.id);
+        self.retired.pending.store(true, Ordering::Release);
+    }
+}
+
+impl Rring {
+    // Prepares a cancel for the poll of every waker dropped since the last
+    // call. A poll that doesn't fit into the SQ stays queued for next time.
+    pub(crate) fn cancel_retired_wakers(&self) {
+        if !self.retired_wakers.pending.swap(false, Ordering::Acquire) {
+            return;
+        }
+        let mut ids = self.retired_wakers.ids.lock().unwrap();
+        while let Some(&id) = ids.last() {
+            let Ok(mut sqe) = self.get_sqe() else {
+                self.retired_wakers.pending.store(true, Ordering::Release);
+                return;
+            };
+            sqe.cancel(tag::encode(TagKind::Waker, id), 0);
+            sqe.set_tag(TagKind::Cancel, 0);
+            ids.pop();
+        }
+    }
+}
+
+impl RringWaker {
+    pub(crate) fn new(ring: &Rring) -> Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fd = EventFd {
+            fd,
+            id: ring.retired_wakers.next_id.fetch_add(1, Ordering::Relaxed),
+            retired: Arc::clone(&ring.retired_wakers),
+        };
+        let mut sqe = ring.get_sqe()?;
+        sqe.poll_multishot(fd.fd, libc::POLLIN);
+        sqe.set_tag(TagKind::Waker, fd.id);
+        ring.submit_robust()?;
+        Ok(Self { fd: Arc::new(fd) })
+    }
+    pub fn wake(&self) -> Result<()> {
+        let one: u64 = 1;
+        let ret = unsafe { libc::write(self.fd.fd, (&one as *const u64).cast(), 8) };
+        // EAGAIN means the counter is saturated, i.e. a wake is pending anyway.
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EAGAIN) {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::{Rring, SetupFlag};
+
+    #[test]
+    fn wake_unblocks_a_thread_waiting_on_the_ring() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let waker = ring.waker().unwrap();
+        let woken = Arc::new(AtomicBool::new(false));
+        let waking = {
+            let (waker, woken) = (waker.clone(), Arc::clone(&woken));
+            thread::spawn(move || {
+                // Give the ring time to block first.
+                thread::sleep(Duration::from_millis(50));
+                woken.store(true, Ordering::SeqCst);
+                waker.wake().unwrap();
+            })
+        };
+
+        let cqe = ring.wait().unwrap();
+        assert!(woken.load(Ordering::SeqCst));
+        assert!(cqe.is_wake());
+        // The multishot poll stays armed for the next wake.
+        assert!(cqe.has_more());
+        ring.seen(cqe);
+        waking.join().unwrap();
+    }
+
+    #[test]
+    fn dropping_the_last_waker_cancels_its_poll() {
+        let ring = Rring::new(8, SetupFlag::empty()).unwrap();
+        let waker = ring.waker().unwrap();
+        let clone = waker.clone();
+        drop(waker);
+        // A live clone keeps the poll armed.
+        ring.submit().unwrap();
+        assert!(ring.peek().is_none());
+        assert_eq!(ring.stats().in_flight, 1);
+
+        std::thread::spawn(move || drop(clone)).join().unwrap();
+        assert_eq!(ring.submit().unwrap(), 1);
+        let mut wakes = 0;
+        for _ in 0..2 {
+            let cqe = ring.wait().unwrap();
+            if cqe.is_wake() {
+                assert!(cqe.was_cancelled());
+                wakes += 1;
+            } else {
+                assert_eq!(cqe.get_result(), 0);
+            }
+            ring.seen(cqe);
+        }
+        assert_eq!(wakes, 1);
+        assert_eq!(ring.stats().in_flight, 0);
+    }
+}